@@ -1,5 +1,22 @@
+extern crate alloc;
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::path::Path;
 use std::process::Command;
 
+#[allow(dead_code)]
+#[path = "src/backtrace/encode.rs"]
+mod encode;
+use encode::encode;
+
+/// Size of the symbol table embedded in the kernel, see `src/backtrace/symbols.rs`
+///
+/// The table is padded to this size, so embedding the symbols of a previous
+/// build doesn't move any code.
+const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+
 fn main() {
     let git_output = Command::new("git").args(&["rev-parse", "HEAD"]).output().unwrap();
     let git_hash = String::from_utf8(git_output.stdout).unwrap();
@@ -8,4 +25,78 @@ fn main() {
     let date_output = Command::new("git").args(&["show", "-s", "--format=%ci", "HEAD"]).output().unwrap();
     let date = String::from_utf8(date_output.stdout).unwrap();
     println!("cargo:rustc-env=GIT_HASH_DATE={}", date);
-}
\ No newline at end of file
+    git_rerun();
+
+    symbol_table();
+}
+
+/// Reruns the build script when a commit or checkout changes `HEAD`
+///
+/// The other `rerun-if` lines stop cargo from rerunning it on every change,
+/// which would leave `GIT_HASH` stale.
+fn git_rerun() {
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).output().ok().filter(|output| output.status.success())?;
+        Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+    };
+    let dir = match git(&["rev-parse", "--git-dir"]) {
+        Some(dir) => Path::new(&dir).to_path_buf(),
+        None => return,
+    };
+    let mut files = vec![dir.join("HEAD")];
+    // the branch HEAD points to, which moves on commits, and `git gc` packs it
+    if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        files.push(dir.join(branch));
+        files.push(dir.join("packed-refs"));
+    }
+    // cargo always reruns for a file that does not exist
+    for file in files.iter().filter(|file| file.exists()) {
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+}
+
+/// Writes `symbols.bin` from the `nm -n -S -C` output in `KERNEL_SYMBOL_MAP`, or an empty table
+fn symbol_table() {
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMBOL_MAP");
+    let mut symbols = Vec::new();
+    if let Some(path) = env::var_os("KERNEL_SYMBOL_MAP") {
+        println!("cargo:rerun-if-changed={}", Path::new(&path).display());
+        let map = fs::read_to_string(&path).expect("failed to read KERNEL_SYMBOL_MAP");
+        symbols = map.lines().filter_map(parse_symbol).collect();
+        symbols.sort_by_key(|symbol| symbol.0);
+        symbols.dedup_by_key(|symbol| symbol.0);
+    }
+
+    let mut table = encode(&symbols);
+    if table.len() > SYMBOL_TABLE_SIZE {
+        println!("cargo:warning=symbol table is {} bytes, only {} fit, backtraces have no names", table.len(), SYMBOL_TABLE_SIZE);
+        table = encode::<&str>(&[]);
+    }
+    table.resize(SYMBOL_TABLE_SIZE, 0);
+    let out = Path::new(&env::var_os("OUT_DIR").unwrap()).join("symbols.bin");
+    fs::write(out, table).unwrap();
+}
+
+/// Parses a function symbol from a line like `ffff800000001000 0000000000000042 T kernel_main`
+fn parse_symbol(line: &str) -> Option<(u64, u32, String)> {
+    let mut fields = line.splitn(4, ' ');
+    let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let mut field = fields.next()?;
+    let mut size = 0;
+    if field.len() > 1 {
+        size = u32::try_from(u64::from_str_radix(field, 16).ok()?).ok()?;
+        field = fields.next()?;
+    }
+    if !matches!(field, "t" | "T" | "w" | "W") {
+        return None;
+    }
+    let mut name = fields.collect::<Vec<_>>().join(" ");
+    // legacy Rust mangling ends the demangled path with a `::h` and 16 hex digits hash
+    if let Some(index) = name.rfind("::h") {
+        let hash = &name[index + 3..];
+        if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            name.truncate(index);
+        }
+    }
+    Some((address, size, name))
+}
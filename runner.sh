@@ -0,0 +1,17 @@
+#!/bin/sh
+# Cargo runner for the kernel, see .cargo/config.toml
+#
+# Creates the disk image tests/ata.rs runs on, then runs the kernel with
+# `bootimage runner`, which attaches the image to test binaries, see
+# `test-args` in Cargo.toml. The image is sparse and attached with
+# `snapshot=on`, so recreating it every time is cheap and tests never
+# change it.
+set -e
+
+# keep in sync with DISK_SECTORS in tests/ata.rs
+sectors=16384
+
+dir="$(dirname "$0")/target"
+mkdir -p "$dir"
+dd if=/dev/null of="$dir/ata-test.img" bs=512 seek="$sectors" 2>/dev/null
+exec bootimage runner "$@"
@@ -0,0 +1,157 @@
+//! Minimal ACPI table parsing
+//!
+//! Only what is needed to find the processors and PCI configuration space:
+//! the RSDP handed over by the bootloader, the RSDT or XSDT it points to,
+//! the MADT and the MCFG.
+
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+const SDT_HEADER_SIZE: usize = 36;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    BadRsdp,
+    /// A table's checksum does not add up
+    BadChecksum([u8; 4]),
+    TableNotFound([u8; 4]),
+}
+
+/// A processor listed in the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    /// Set if the processor can be started, disabled ones must be left alone
+    pub enabled: bool,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub processors: Vec<Processor>,
+}
+
+/// A PCI segment's memory mapped configuration space, listed in the MCFG
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+fn read<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>()) }
+}
+
+fn checksum_ok(addr: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Returns the addresses of all tables listed in the RSDT or XSDT
+fn tables(rsdp: PhysAddr) -> Result<Vec<PhysAddr>, AcpiError> {
+    if read::<[u8; 8]>(rsdp) != *b"RSD PTR " || !checksum_ok(rsdp, 20) {
+        return Err(AcpiError::BadRsdp);
+    }
+    let revision: u8 = read(rsdp + 15u64);
+
+    // the XSDT holds 64 bit pointers and is preferred if present
+    let (root, entry_size) = if revision >= 2 {
+        (PhysAddr::new(read::<u64>(rsdp + 24u64)), 8)
+    } else {
+        (PhysAddr::new(u64::from(read::<u32>(rsdp + 16u64))), 4)
+    };
+    let len = read::<u32>(root + 4u64) as usize;
+    if !checksum_ok(root, len) {
+        return Err(AcpiError::BadChecksum(read(root)));
+    }
+
+    let entries = (len - SDT_HEADER_SIZE) / entry_size;
+    Ok((0..entries)
+        .map(|index| {
+            let entry = root + (SDT_HEADER_SIZE + index * entry_size) as u64;
+            if entry_size == 8 {
+                PhysAddr::new(read::<u64>(entry))
+            } else {
+                PhysAddr::new(u64::from(read::<u32>(entry)))
+            }
+        })
+        .collect())
+}
+
+/// Finds the table with the given signature and checks its checksum
+fn find_table(rsdp: PhysAddr, signature: [u8; 4]) -> Result<(PhysAddr, usize), AcpiError> {
+    let table = tables(rsdp)?
+        .into_iter()
+        .find(|table| read::<[u8; 4]>(*table) == signature)
+        .ok_or(AcpiError::TableNotFound(signature))?;
+    let len = read::<u32>(table + 4u64) as usize;
+    if !checksum_ok(table, len) {
+        return Err(AcpiError::BadChecksum(signature));
+    }
+    Ok((table, len))
+}
+
+/// Reads the processor list from the MADT ("APIC" table)
+pub fn madt(rsdp: PhysAddr) -> Result<Madt, AcpiError> {
+    const LOCAL_APIC: u8 = 0;
+    const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+    let (table, len) = find_table(rsdp, *b"APIC")?;
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(read::<u32>(table + SDT_HEADER_SIZE as u64))),
+        processors: Vec::new(),
+    };
+
+    // entries start after the local APIC address and flags
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= len {
+        let entry = table + offset as u64;
+        let kind: u8 = read(entry);
+        let entry_len = read::<u8>(entry + 1u64) as usize;
+        if entry_len < 2 {
+            break;
+        }
+        match kind {
+            LOCAL_APIC => {
+                let flags: u32 = read(entry + 4u64);
+                madt.processors.push(Processor {
+                    acpi_id: read(entry + 2u64),
+                    apic_id: read(entry + 3u64),
+                    enabled: flags & 1 != 0,
+                });
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = PhysAddr::new(read(entry + 4u64));
+            }
+            _ => {}
+        }
+        offset += entry_len;
+    }
+    Ok(madt)
+}
+
+/// Reads the PCI Express configuration space regions from the MCFG
+pub fn mcfg(rsdp: PhysAddr) -> Result<Vec<EcamRegion>, AcpiError> {
+    const ENTRY_SIZE: usize = 16;
+
+    let (table, len) = find_table(rsdp, *b"MCFG")?;
+    // entries start after 8 reserved bytes
+    let mut regions = Vec::new();
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + ENTRY_SIZE <= len {
+        let entry = table + offset as u64;
+        regions.push(EcamRegion {
+            base: PhysAddr::new(read(entry)),
+            segment: read(entry + 8u64),
+            start_bus: read(entry + 10u64),
+            end_bus: read(entry + 11u64),
+        });
+        offset += ENTRY_SIZE;
+    }
+    Ok(regions)
+}
@@ -0,0 +1,439 @@
+//! ATA PIO driver for IDE controllers
+//!
+//! Binds to PCI IDE controllers and drives the channels that are in
+//! compatibility mode, at the legacy ports with IRQ 14 and 15. Each drive
+//! found by IDENTIFY is registered as a block device `ata0`-`ata3`
+//! (primary master, primary slave, secondary master, secondary slave).
+//! Data moves through the data port, one 512 byte sector per interrupt.
+//!
+//! In QEMU the boot image is the primary master; attach a raw image as
+//! another drive to test with, e.g.
+//! `-drive file=disk.img,format=raw,if=ide,index=1`. `cargo test` attaches
+//! the image `runner.sh` creates, see `tests/ata.rs`.
+
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use futures_util::future::BoxFuture;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+use crate::block::{self, BlockDevice, BlockError};
+use crate::pci::{self, Device, Driver, DriverError, Match};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Sectors per command, the most LBA28 commands can transfer
+const MAX_SECTORS: usize = 256;
+
+// status register bits
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const COMMAND_READ: u8 = 0x20;
+const COMMAND_READ_EXT: u8 = 0x24;
+const COMMAND_WRITE: u8 = 0x30;
+const COMMAND_WRITE_EXT: u8 = 0x34;
+const COMMAND_FLUSH: u8 = 0xe7;
+const COMMAND_FLUSH_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// Status polls before a drive that doesn't become ready is given up on
+const POLL_LIMIT: usize = 1_000_000;
+
+/// Timer ticks without a completion interrupt before the status is polled
+/// instead, in case the interrupt got lost
+const IRQ_TIMEOUT: usize = 2;
+
+/// Timer ticks before a drive that stays busy is given up on, about ten
+/// seconds at the PIT's default rate
+const COMMAND_TIMEOUT: usize = 200;
+
+/// Set by `force_lba48`
+static FORCE_LBA48: AtomicBool = AtomicBool::new(false);
+
+/// Completion interrupts of a channel, set by the interrupt handler
+struct ChannelIrq {
+    fired: AtomicBool,
+    waker: AtomicWaker,
+}
+
+static IRQS: [ChannelIrq; 2] = [
+    ChannelIrq { fired: AtomicBool::new(false), waker: AtomicWaker::new() },
+    ChannelIrq { fired: AtomicBool::new(false), waker: AtomicWaker::new() },
+];
+
+/// Legacy command block and control ports, and the IRQ, of each channel
+const LEGACY_CHANNELS: [(u16, u16, u8); 2] = [(0x1f0, 0x3f6, 14), (0x170, 0x376, 15)];
+
+/// Called by the timer interrupt, lets `Channel::wait_irq` notice lost interrupts
+///
+/// Must not block or allocate
+pub(crate) fn tick() {
+    for irq in &IRQS {
+        irq.waker.wake();
+    }
+}
+
+/// Called by the IRQ 14 and 15 handlers
+///
+/// Must not block or allocate
+pub(crate) fn interrupt(channel: usize) {
+    // reading the status register acknowledges the interrupt on the drive
+    let mut status: Port<u8> = Port::new(LEGACY_CHANNELS[channel].0 + 7);
+    unsafe { status.read() };
+    IRQS[channel].fired.store(true, Ordering::Release);
+    IRQS[channel].waker.wake();
+}
+
+/// Result of IDENTIFY DEVICE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identify {
+    pub model: String,
+    pub serial: String,
+    pub sectors: u64,
+    pub lba48: bool,
+}
+
+impl Identify {
+    pub fn parse(words: &[u16; 256]) -> Identify {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            words[100..104].iter().rev().fold(0, |sectors, word| sectors << 16 | u64::from(*word))
+        } else {
+            u64::from(words[60]) | u64::from(words[61]) << 16
+        };
+        Identify {
+            model: ata_string(&words[27..47]),
+            serial: ata_string(&words[10..20]),
+            sectors,
+            lba48,
+        }
+    }
+}
+
+/// Strings hold two characters per word, the first in the high byte
+fn ata_string(words: &[u16]) -> String {
+    let string: String = words.iter().flat_map(|word| word.to_be_bytes()).map(char::from).collect();
+    String::from(string.trim())
+}
+
+/// One of the two channels of a controller, shared by its master and slave
+struct Channel {
+    index: usize,
+    base: u16,
+    control: u16,
+    /// Held for a whole command, the drives of a channel share its registers
+    lock: crate::sync::Mutex<()>,
+}
+
+impl Channel {
+    fn port(&self, register: u16) -> Port<u8> {
+        Port::new(self.base + register)
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { self.port(register).write(value) }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { self.port(register).read() }
+    }
+
+    /// Reading the alternate status doesn't acknowledge interrupts
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    /// The drive needs 400ns after a command or selection to update its status
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn check(&self, status: u8) -> Result<u8, BlockError> {
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Device(self.read(1)));
+        }
+        Ok(status)
+    }
+
+    /// Selects the drive, then sets up the address and count registers
+    ///
+    /// A count of 256 is written as 0, which LBA28 commands take as 256
+    fn select(&self, slave: bool, lba: u64, count: u16, lba48: bool) {
+        let drive = 0xe0 | u8::from(slave) << 4;
+        if lba48 {
+            self.write(6, drive);
+            self.delay();
+            // high bytes first, each register holds two
+            self.write(2, (count >> 8) as u8);
+            self.write(3, (lba >> 24) as u8);
+            self.write(4, (lba >> 32) as u8);
+            self.write(5, (lba >> 40) as u8);
+        } else {
+            self.write(6, drive | ((lba >> 24) & 0xf) as u8);
+            self.delay();
+        }
+        self.write(2, count as u8);
+        self.write(3, lba as u8);
+        self.write(4, (lba >> 8) as u8);
+        self.write(5, (lba >> 16) as u8);
+    }
+
+    fn command(&self, command: u8) {
+        IRQS[self.index].fired.store(false, Ordering::Release);
+        self.write(7, command);
+        self.delay();
+    }
+
+    /// Waits for the drive's interrupt, returns the status
+    ///
+    /// Without an interrupt for `IRQ_TIMEOUT` ticks, a drive that is no
+    /// longer busy counts as done. `tick` wakes the waiting task to check.
+    async fn wait_irq(&self) -> Result<u8, BlockError> {
+        let irq = &IRQS[self.index];
+        let start = crate::time::get();
+        futures_util::future::poll_fn(|cx| {
+            if irq.fired.swap(false, Ordering::AcqRel) {
+                return Poll::Ready(Ok(()));
+            }
+            let elapsed = crate::time::get().wrapping_sub(start);
+            if elapsed >= IRQ_TIMEOUT && self.alt_status() & STATUS_BSY == 0 {
+                log::debug!("ata: lost interrupt on channel {}", self.index);
+                return Poll::Ready(Ok(()));
+            }
+            if elapsed >= COMMAND_TIMEOUT {
+                return Poll::Ready(Err(BlockError::Timeout));
+            }
+            irq.waker.register(cx.waker());
+            if irq.fired.swap(false, Ordering::AcqRel) {
+                irq.waker.take();
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await?;
+        self.check(self.alt_status())
+    }
+
+    fn read_sector(&self, sector: &mut [u8]) {
+        let mut data: Port<u16> = Port::new(self.base);
+        for bytes in sector.chunks_exact_mut(2) {
+            bytes.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, sector: &[u8]) {
+        let mut data: Port<u16> = Port::new(self.base);
+        for bytes in sector.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+        }
+    }
+
+    /// Runs IDENTIFY DEVICE, polling; `None` if there is no ATA drive
+    fn identify(&self, slave: bool) -> Option<Identify> {
+        self.select(slave, 0, 0, false);
+        self.command(COMMAND_IDENTIFY);
+        // 0 without a drive, 0xff if nothing drives the bus at all
+        let status = self.alt_status();
+        if status == 0 || status == 0xff {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // ATAPI and SATA drives set a signature here and abort the command
+        if self.read(4) != 0 || self.read(5) != 0 {
+            return None;
+        }
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_ERR != 0 {
+                return None;
+            }
+            if status & STATUS_DRQ != 0 {
+                let mut bytes = [0; SECTOR_SIZE];
+                self.read_sector(&mut bytes);
+                let mut words = [0; 256];
+                for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+                    *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                return Some(Identify::parse(&words));
+            }
+        }
+        None
+    }
+}
+
+pub struct Drive {
+    channel: Arc<Channel>,
+    slave: bool,
+    name: String,
+    pub identify: Identify,
+}
+
+impl Drive {
+    fn uses_lba48(&self, lba: u64, count: usize) -> bool {
+        self.identify.lba48 && (lba + count as u64 > 1 << 28 || FORCE_LBA48.load(Ordering::Relaxed))
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.channel.lock.lock().await;
+        for (index, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (index * MAX_SECTORS) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.uses_lba48(lba, count);
+            self.channel.wait_not_busy()?;
+            self.channel.select(self.slave, lba, count as u16, lba48);
+            self.channel.command(if lba48 { COMMAND_READ_EXT } else { COMMAND_READ });
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                if self.channel.wait_irq().await? & STATUS_DRQ == 0 {
+                    return Err(BlockError::Device(self.channel.read(1)));
+                }
+                self.channel.read_sector(sector);
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        // nothing to flush, and no drive selected to flush it
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.channel.lock.lock().await;
+        let mut lba48 = false;
+        for (index, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = lba + (index * MAX_SECTORS) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            lba48 = self.uses_lba48(lba, count);
+            self.channel.wait_not_busy()?;
+            self.channel.select(self.slave, lba, count as u16, lba48);
+            self.channel.command(if lba48 { COMMAND_WRITE_EXT } else { COMMAND_WRITE });
+            // the first sector is requested without an interrupt, the others after one each
+            let mut status = self.channel.check(self.channel.wait_not_busy()?)?;
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                if status & STATUS_DRQ == 0 {
+                    return Err(BlockError::Device(self.channel.read(1)));
+                }
+                self.channel.write_sector(sector);
+                status = self.channel.wait_irq().await?;
+            }
+        }
+        self.channel.command(if lba48 { COMMAND_FLUSH_EXT } else { COMMAND_FLUSH });
+        self.channel.wait_irq().await?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for Drive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.identify.sectors
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(), BlockError>> {
+        Box::pin(self.read(lba, buf))
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<(), BlockError>> {
+        Box::pin(self.write(lba, buf))
+    }
+}
+
+/// Lets IRQ 14 or 15 through the slave PIC, and the slave through the master
+fn unmask_irq(irq: u8) {
+    let mut master: Port<u8> = Port::new(0x21);
+    let mut slave: Port<u8> = Port::new(0xa1);
+    unsafe {
+        master.write(master.read() & !(1 << 2));
+        slave.write(slave.read() & !(1 << (irq - 8)));
+    }
+}
+
+static DRIVER: Driver = Driver {
+    name: "ata",
+    matches: &[Match::Class { class: 0x01, subclass: Some(0x01), prog_if: None }],
+    probe,
+};
+
+fn probe(device: &Device) -> Result<(), DriverError> {
+    // bit 0 and 2 of the programming interface are set for channels in native mode
+    let compatible: Vec<usize> = (0..2).filter(|channel| device.prog_if & (1 << (channel * 2)) == 0).collect();
+    if compatible.is_empty() {
+        return Err(DriverError::Unsupported);
+    }
+    device.set_command(device.command() | pci::COMMAND_IO);
+    for index in compatible {
+        let (base, control, irq) = LEGACY_CHANNELS[index];
+        let channel = Arc::new(Channel { index, base, control, lock: crate::sync::Mutex::new(()) });
+        unmask_irq(irq);
+        for slave in [false, true] {
+            if let Some(identify) = channel.identify(slave) {
+                let name = format!("ata{}", index * 2 + usize::from(slave));
+                log::info!("{}: {}{}", name, identify.model, if identify.lba48 { ", LBA48" } else { "" });
+                block::register(Arc::new(Drive { channel: channel.clone(), slave, name, identify }));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Registers the driver, must be called before `pci::init`
+pub fn init() {
+    pci::driver::register(&DRIVER);
+}
+
+/// Uses LBA48 commands for all requests to drives supporting them, not
+/// only past the 2^28 sectors LBA28 can address
+///
+/// Lets the tests cover both addressing modes on a small disk.
+pub fn force_lba48(force: bool) {
+    FORCE_LBA48.store(force, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_parse_identify() {
+    let mut words = [0; 256];
+    // "QEMU HARDDISK" padded with spaces
+    for (word, pair) in words[27..47].iter_mut().zip(b"QEMU HARDDISK                           ".chunks(2)) {
+        *word = u16::from_be_bytes([pair[0], pair[1]]);
+    }
+    words[60] = 0x0000;
+    words[61] = 0x0002;
+    let identify = Identify::parse(&words);
+    assert_eq!(identify.model, "QEMU HARDDISK");
+    assert_eq!(identify.sectors, 0x2_0000);
+    assert!(!identify.lba48);
+
+    words[83] = 1 << 10;
+    words[100] = 0x0000;
+    words[101] = 0x0000;
+    words[102] = 0x0001;
+    assert_eq!(Identify::parse(&words).sectors, 1 << 32);
+}
@@ -0,0 +1,27 @@
+//! Layout of the symbol table, shared with `build.rs`, which includes this file
+//!
+//! `b"KSYM"`, the symbol count, then per symbol its address, size, name
+//! offset and name length, then the names. Numbers are little endian.
+
+use alloc::vec::Vec;
+
+pub const MAGIC: &[u8] = b"KSYM";
+pub const HEADER_LEN: usize = 8;
+pub const ENTRY_LEN: usize = 20;
+
+/// Encodes `(address, size, name)` triples, which must be sorted by address
+pub fn encode<S: AsRef<str>>(symbols: &[(u64, u32, S)]) -> Vec<u8> {
+    let mut table = MAGIC.to_vec();
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    let mut names = Vec::new();
+    for (address, size, name) in symbols {
+        let name = name.as_ref();
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    table
+}
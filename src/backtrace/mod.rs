@@ -0,0 +1,120 @@
+//! Stack backtraces for panics and exceptions
+//!
+//! The target spec forces frame pointers, so every function starts by
+//! pushing the caller's `rbp` and pointing `rbp` at it. The return address
+//! sits right above, which makes the stack a linked list of frames. Interrupt
+//! handlers continue the list into the interrupted code, the first return
+//! address after them is the interrupted instruction.
+
+pub mod encode;
+pub mod symbols;
+
+use core::arch::asm;
+use core::fmt;
+use x86_64::VirtAddr;
+pub use symbols::{Symbol, SymbolTable};
+
+/// Frames walked before giving up, in case the chain is corrupted
+pub const MAX_FRAMES: usize = 64;
+
+/// A return address on the stack, or the address of an interrupted instruction
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub address: VirtAddr,
+}
+
+impl Frame {
+    pub fn new(address: VirtAddr) -> Frame {
+        Frame { address }
+    }
+
+    pub fn symbol(&self) -> Option<Symbol<'static>> {
+        SymbolTable::kernel().lookup(self.address.as_u64())
+    }
+}
+
+impl fmt::Display for Frame {
+    /// `address  function+offset`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}  ", self.address.as_u64())?;
+        match self.symbol() {
+            Some(symbol) => write!(f, "{}+{:#x}", symbol.name, symbol.offset),
+            None => f.write_str("<unknown>"),
+        }
+    }
+}
+
+/// Iterator over the frames of the current stack, innermost first
+///
+/// Walking stops at a null, misaligned or unmapped frame pointer, so it
+/// doesn't fault on stacks that aren't chained all the way.
+pub struct Frames {
+    rbp: u64,
+    remaining: usize,
+}
+
+impl Frames {
+    /// Starts at the function calling `capture`
+    #[inline(never)]
+    pub fn capture() -> Frames {
+        let rbp: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        // the first frame returns into `capture`'s caller
+        Frames { rbp, remaining: MAX_FRAMES }
+    }
+
+    /// Starts at the frame `rbp` points to, e.g. one saved in a trap frame
+    pub fn from_rbp(rbp: u64) -> Frames {
+        Frames { rbp, remaining: MAX_FRAMES }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.remaining == 0 || self.rbp == 0 || self.rbp % 8 != 0 {
+            return None;
+        }
+        let rbp = VirtAddr::try_new(self.rbp).ok()?;
+        if !crate::memory::is_mapped(rbp) || !crate::memory::is_mapped(rbp + 15u64) {
+            return None;
+        }
+        let (caller_rbp, return_address) = unsafe {
+            let frame = rbp.as_ptr::<u64>();
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0 {
+            return None;
+        }
+        self.rbp = caller_rbp;
+        self.remaining -= 1;
+        Some(Frame::new(VirtAddr::try_new(return_address).ok()?))
+    }
+}
+
+/// Prints the backtrace of the caller on serial and screen
+#[inline(never)]
+pub fn print() {
+    crate::serial_println!("backtrace:");
+    crate::println!("backtrace:");
+    // skip the return address into `print` itself
+    for frame in Frames::capture().skip(1) {
+        crate::serial_println!("  {}", frame);
+        crate::println!("  {}", frame);
+    }
+}
+
+#[test_case]
+fn test_capture() {
+    #[inline(never)]
+    fn nested() -> usize {
+        Frames::capture().count()
+    }
+
+    let outer = Frames::capture().count();
+    assert!(outer > 0);
+    assert_eq!(nested(), outer + 1);
+}
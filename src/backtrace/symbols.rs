@@ -0,0 +1,103 @@
+//! Function names of the kernel, embedded at build time
+//!
+//! `build.rs` encodes the output of `nm -n -S -C` on a previous build of the
+//! kernel, given as the `KERNEL_SYMBOL_MAP` environment variable, into a table
+//! of a fixed size. As the size doesn't depend on the symbols, embedding them
+//! doesn't move any code, so the second build's addresses match the map.
+//! `with-symbols.sh` does both builds, e.g. `./with-symbols.sh run`.
+//! The map is only valid for the kernel binary, so tests are built without it.
+//!
+//! Without a map the table is empty and backtraces show addresses only.
+
+use core::convert::TryInto;
+use super::encode::{ENTRY_LEN, HEADER_LEN, MAGIC};
+
+static TABLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin"));
+
+/// Sorted symbols in the format of `encode`
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+}
+
+/// The function an address belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// Distance of the address from the function's start
+    pub offset: u64,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// The table embedded in the kernel
+    pub fn kernel() -> SymbolTable<'static> {
+        SymbolTable::parse(TABLE).unwrap_or(SymbolTable { entries: &[], names: &[] })
+    }
+
+    pub fn parse(data: &'a [u8]) -> Option<SymbolTable<'a>> {
+        if data.get(..4)? != MAGIC {
+            return None;
+        }
+        let count = u32::from_le_bytes(data.get(4..HEADER_LEN)?.try_into().ok()?) as usize;
+        let names_start = HEADER_LEN + count * ENTRY_LEN;
+        Some(SymbolTable {
+            entries: data.get(HEADER_LEN..names_start)?,
+            names: data.get(names_start..)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Address, size, name offset and name length of the symbol at `index`
+    fn entry(&self, index: usize) -> (u64, u32, u32, u32) {
+        let entry = &self.entries[index * ENTRY_LEN..(index + 1) * ENTRY_LEN];
+        let u32_at = |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+        (u64::from_le_bytes(entry[..8].try_into().unwrap()), u32_at(8), u32_at(12), u32_at(16))
+    }
+
+    /// Finds the function containing `address`
+    ///
+    /// Symbols without a size are assumed to extend to the next symbol.
+    pub fn lookup(&self, address: u64) -> Option<Symbol<'a>> {
+        // index of the first symbol after `address`
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.entry(middle).0 <= address {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        let (start, size, name_offset, name_len) = self.entry(low.checked_sub(1)?);
+        let offset = address - start;
+        if size != 0 && offset >= u64::from(size) {
+            return None;
+        }
+        let name = self.names.get(name_offset as usize..(name_offset + name_len) as usize)?;
+        Some(Symbol {
+            name: core::str::from_utf8(name).ok()?,
+            offset,
+        })
+    }
+}
+
+#[test_case]
+fn test_lookup() {
+    let data = super::encode::encode(&[(0x1000, 0x20, "first"), (0x1040, 0, "second")]);
+    let table = SymbolTable::parse(&data).unwrap();
+    assert_eq!(table.len(), 2);
+    assert_eq!(table.lookup(0xfff), None);
+    assert_eq!(table.lookup(0x1010), Some(Symbol { name: "first", offset: 0x10 }));
+    // past the end of `first`
+    assert_eq!(table.lookup(0x1030), None);
+    assert_eq!(table.lookup(0x2000), Some(Symbol { name: "second", offset: 0xfc0 }));
+    assert!(SymbolTable::parse(b"nope").is_none());
+}
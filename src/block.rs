@@ -0,0 +1,67 @@
+//! Block devices
+//!
+//! Drivers implement `BlockDevice` and `register` their devices, users
+//! look them up by name. Requests transfer whole blocks and complete
+//! asynchronously, so tasks can wait for the disk without blocking the
+//! executor.
+
+use alloc::{sync::Arc, vec::Vec};
+use futures_util::future::BoxFuture;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last block
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks
+    BadBuffer,
+    /// The device reported an error, with its error register
+    Device(u8),
+    /// The device stopped responding
+    Timeout,
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Name like `ata0`, unique among the registered devices
+    fn name(&self) -> &str;
+
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads `buf.len() / block_size()` blocks starting at `lba`
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(), BlockError>>;
+
+    /// Writes `buf.len() / block_size()` blocks starting at `lba`, they
+    /// are on the medium when the future completes
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<(), BlockError>>;
+}
+
+/// Checks that `len` bytes at `lba` are whole blocks on the device,
+/// returns the number of blocks
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if len % device.block_size() != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / device.block_size()) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+pub fn register(device: Arc<dyn BlockDevice>) {
+    log::info!("block device {}: {} blocks of {} bytes", device.name(), device.block_count(), device.block_size());
+    DEVICES.lock().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn by_name(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
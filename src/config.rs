@@ -0,0 +1,179 @@
+//! Kernel command line and the boot configuration parsed from it
+//!
+//! The command line is a whitespace separated list of `key=value` options.
+//! It is embedded at build time from the `KERNEL_CMDLINE` environment
+//! variable, the bootloader has no way to pass one. Parsing does not
+//! allocate, so the configuration is available before the heap, whose size
+//! it sets.
+//!
+//! | option     | value                                            | default                    |
+//! |------------|--------------------------------------------------|----------------------------|
+//! | `gdb`      | `com1`-`com4` to run the GDB stub on, or `off`   | `off`                      |
+//! | `heap`     | size in bytes, with an optional `K`, `M` or `G`  | `16M`                      |
+//! | `log`      | filter directives, see `klog::filter`            | `trace`, `info` in release |
+//! | `logcolor` | `on`, or `off` for serial output without ANSI    | `on`                       |
+//! | `panic`    | `halt`, `monitor`, or `reboot` to keep the log   | `halt`                     |
+//! | `term`     | `log`, `console`, `gui`, `game`, `tasks`, `test` | `console`                  |
+//! | `test`     | only run tests whose name contains the value     | all tests                  |
+
+use conquer_once::spin::OnceCell;
+use log::LevelFilter;
+use crate::klog::Filter;
+use crate::serial::ComPort;
+use crate::vga::term::VirtualTerminals;
+
+/// Command line embedded at build time
+pub const EMBEDDED: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+static CONFIG: OnceCell<BootConfig> = OnceCell::uninit();
+
+const DEFAULT_LOG_LEVEL: LevelFilter = if cfg!(debug_assertions) { LevelFilter::Trace } else { LevelFilter::Info };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Exit QEMU with a failure code, or halt on real hardware
+    Halt,
+    /// Enter the kernel monitor, see `monitor`
+    Monitor,
+    /// Warm reboot, which keeps the panic log, see `panic_log`
+    Reboot,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BootConfig {
+    pub cmdline: &'static str,
+    /// Port of the GDB stub, see `gdb`
+    pub gdb_port: Option<ComPort>,
+    pub heap_size: usize,
+    pub log_filter: Filter,
+    /// ANSI colors in the serial log
+    pub log_color: bool,
+    /// What the panic handler does after printing the panic
+    pub panic: PanicAction,
+    /// Virtual terminal focused at boot
+    pub terminal: VirtualTerminals,
+    pub test_filter: Option<&'static str>,
+}
+
+impl BootConfig {
+    /// Parses the command line, invalid values are left at their default
+    /// and reported by `report_invalid`
+    pub fn parse(cmdline: &'static str) -> BootConfig {
+        let mut config = BootConfig {
+            cmdline,
+            gdb_port: None,
+            heap_size: 16 * 1024 * 1024,
+            log_filter: Filter::new(DEFAULT_LOG_LEVEL),
+            log_color: true,
+            panic: PanicAction::Halt,
+            terminal: VirtualTerminals::Console,
+            test_filter: None,
+        };
+        for (key, value) in options(cmdline) {
+            config.apply(key, value).ok();
+        }
+        config
+    }
+
+    fn apply(&mut self, key: &str, value: &'static str) -> Result<(), &'static str> {
+        match key {
+            "gdb" => self.gdb_port = parse_port(value).ok_or("expected com1-com4 or off")?,
+            "heap" => self.heap_size = parse_size(value).ok_or("expected a size like 16M")?,
+            "log" => self.log_filter = Filter::parse(value, DEFAULT_LOG_LEVEL).map_err(|_| "expected log filter directives")?,
+            "logcolor" => self.log_color = parse_switch(value).ok_or("expected on or off")?,
+            "panic" => self.panic = match value {
+                "halt" => PanicAction::Halt,
+                "monitor" => PanicAction::Monitor,
+                "reboot" => PanicAction::Reboot,
+                _ => return Err("expected halt, monitor or reboot"),
+            },
+            "term" => self.terminal = parse_terminal(value).ok_or("unknown terminal")?,
+            "test" => self.test_filter = Some(value),
+            _ => return Err("unknown option"),
+        }
+        Ok(())
+    }
+}
+
+/// Splits the command line into `(key, value)` pairs, a bare flag has an empty value
+pub fn options(cmdline: &str) -> impl Iterator<Item = (&str, &str)> {
+    cmdline.split_whitespace().map(|option| match option.find('=') {
+        Some(index) => (&option[..index], &option[index + 1..]),
+        None => (option, ""),
+    })
+}
+
+/// Parses a size like `65536`, `64K`, `16M` or `1G`
+fn parse_size(value: &str) -> Option<usize> {
+    let (number, unit) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 1024),
+        b'M' | b'm' => (&value[..value.len() - 1], 1024 * 1024),
+        b'G' | b'g' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+fn parse_port(value: &str) -> Option<Option<ComPort>> {
+    Some(match value {
+        "off" => None,
+        "com1" => Some(ComPort::Com1),
+        "com2" => Some(ComPort::Com2),
+        "com3" => Some(ComPort::Com3),
+        "com4" => Some(ComPort::Com4),
+        _ => return None,
+    })
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" | "yes" | "1" => Some(true),
+        "off" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_terminal(value: &str) -> Option<VirtualTerminals> {
+    Some(match value {
+        "log" => VirtualTerminals::KernelLog,
+        "console" => VirtualTerminals::Console,
+        "gui" => VirtualTerminals::GUI,
+        "game" => VirtualTerminals::CanvasGame,
+        "tasks" => VirtualTerminals::Tasks,
+        "test" => VirtualTerminals::ScreenTest,
+        _ => return None,
+    })
+}
+
+/// Returns the boot configuration, parsed from the embedded command line on first use
+pub fn get() -> &'static BootConfig {
+    CONFIG.get_or_init(|| BootConfig::parse(EMBEDDED))
+}
+
+/// Logs the options that were ignored, once the logger is up
+pub fn report_invalid() {
+    let config = get();
+    log::info!("command line: {:?}", config.cmdline);
+    let mut scratch = *config;
+    for (key, value) in options(config.cmdline) {
+        if let Err(reason) = scratch.apply(key, value) {
+            log::warn!("ignoring option {}={:?}: {}", key, value, reason);
+        }
+    }
+}
+
+#[test_case]
+fn test_parse_cmdline() {
+    let config = BootConfig::parse("heap=4M log=warn,rust_stuff::gdt=trace term=tasks test=alloc gdb=com2 bogus heap=x");
+    assert_eq!(config.heap_size, 4 * 1024 * 1024);
+    assert_eq!(config.log_filter.level_for("rust_stuff::memory"), LevelFilter::Warn);
+    assert_eq!(config.log_filter.level_for("rust_stuff::gdt"), LevelFilter::Trace);
+    assert_eq!(config.terminal, VirtualTerminals::Tasks);
+    assert_eq!(config.test_filter, Some("alloc"));
+    assert_eq!(config.gdb_port, Some(ComPort::Com2));
+    assert_eq!(parse_size("64k"), Some(64 * 1024));
+    assert_eq!(parse_size("M"), None);
+}
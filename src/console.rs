@@ -0,0 +1,212 @@
+//! Commands typed on the `Console` virtual terminal
+//!
+//! Keyboard and serial console input is echoed to the terminal and
+//! collected into a line, which is run as a command on enter.
+
+use alloc::string::String;
+use spin::Mutex;
+use crate::{print, println};
+use crate::vga::term::{TERM, VirtualTerminals};
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(args: &str),
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "disk",
+        usage: "disk [NAME LBA]",
+        help: "list the block devices, or dump the start of block LBA of device NAME",
+        run: disk,
+    },
+    Command {
+        name: "log",
+        usage: "log [DIRECTIVES]",
+        help: "show or replace the log filter, e.g. `log info,rust_stuff::peripheral::mouse=trace`",
+        run: log_filter,
+    },
+    Command {
+        name: "logcolor",
+        usage: "logcolor on|off",
+        help: "turn ANSI colors in the serial log on or off",
+        run: log_color,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci [-v]",
+        help: "list the PCI functions, with BARs, interrupts and capabilities for -v",
+        run: lspci,
+    },
+    Command {
+        name: "profile",
+        usage: "profile start|stop|report",
+        help: "sample where the kernel spends its time, `stop` and `report` show the top functions and tasks",
+        run: profile,
+    },
+    Command {
+        name: "monitor",
+        usage: "monitor",
+        help: "stop the kernel and enter the kernel monitor",
+        run: monitor,
+    },
+];
+
+static LINE: Mutex<String> = Mutex::new(String::new());
+
+/// Handles a typed character, called by the keyboard and serial input tasks
+pub fn input(character: char) {
+    crate::task::term::add_char(character);
+    if TERM.lock().active_term != VirtualTerminals::Console {
+        return;
+    }
+    match character {
+        '\n' => {
+            let line = core::mem::take(&mut *LINE.lock());
+            execute(&line);
+        }
+        '\u{8}' => {
+            LINE.lock().pop();
+        }
+        character if character.is_ascii() && !character.is_ascii_control() => LINE.lock().push(character),
+        _ => {}
+    }
+}
+
+/// Runs a command line
+pub fn execute(line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args.trim()),
+        None => println!("unknown command `{}`, try `help`", name),
+    }
+}
+
+fn help(_args: &str) {
+    for command in COMMANDS {
+        println!("{:<20} {}", command.usage, command.help);
+    }
+}
+
+fn log_filter(args: &str) {
+    use crate::klog::{self, Filter};
+
+    if args.is_empty() {
+        println!("{}", klog::filter());
+        return;
+    }
+    match Filter::parse(args, klog::filter().default_level()) {
+        Ok(filter) => {
+            klog::set_filter(filter);
+            println!("{}", filter);
+        }
+        Err(err) => println!("invalid directives: {:?}", err),
+    }
+}
+
+fn log_color(args: &str) {
+    match args {
+        "on" => crate::klog::color::set_ansi(true),
+        "off" => crate::klog::color::set_ansi(false),
+        _ => println!("usage: logcolor on|off"),
+    }
+}
+
+fn disk(args: &str) {
+    use crate::block;
+    use crate::task::executor::{spawn_anywhere, SendTask};
+
+    let mut words = args.split_whitespace();
+    let (name, lba) = match (words.next(), words.next().map(str::parse::<u64>)) {
+        (None, _) => {
+            for device in block::devices() {
+                println!("{}: {} blocks of {} bytes", device.name(), device.block_count(), device.block_size());
+            }
+            return;
+        }
+        (Some(name), Some(Ok(lba))) => (name, lba),
+        _ => {
+            println!("usage: disk [NAME LBA]");
+            return;
+        }
+    };
+    let device = match block::by_name(name) {
+        Some(device) => device,
+        None => {
+            println!("no block device {}", name);
+            return;
+        }
+    };
+    // commands can't wait, the read completes in a task of its own
+    spawn_anywhere(SendTask::new(async move {
+        let mut block = alloc::vec![0; device.block_size()];
+        if let Err(error) = device.read_blocks(lba, &mut block).await {
+            println!("reading block {} of {} failed: {:?}", lba, device.name(), error);
+            return;
+        }
+        for (offset, line) in block.chunks(16).take(8).enumerate() {
+            print!("{:04x}:", offset * 16);
+            for byte in line {
+                print!(" {:02x}", byte);
+            }
+            println!();
+        }
+    }).with_name("disk"));
+}
+
+fn lspci(args: &str) {
+    let verbose = match args {
+        "" => false,
+        "-v" => true,
+        _ => {
+            println!("usage: lspci [-v]");
+            return;
+        }
+    };
+    for device in crate::pci::devices() {
+        println!("{}", device);
+        if !verbose {
+            continue;
+        }
+        for (index, bar) in device.bars.iter().enumerate().filter(|(_, bar)| **bar != crate::pci::Bar::Unused) {
+            println!("    BAR{}: {}", index, bar);
+        }
+        if (1..=4).contains(&device.interrupt_pin) {
+            println!("    interrupt: pin {} routed to IRQ {}", (b'A' + device.interrupt_pin - 1) as char, device.interrupt_line);
+        }
+        for capability in &device.capabilities {
+            println!("    capability {:#04x} at {:#04x}: {}", capability.id, capability.offset, capability.name());
+        }
+    }
+}
+
+fn profile(args: &str) {
+    use crate::profiler;
+
+    match args {
+        "start" => {
+            profiler::start();
+            println!("profiling, `profile stop` shows the results");
+        }
+        "stop" if profiler::is_enabled() => print!("{}", profiler::stop()),
+        "stop" => println!("the profiler isn't running"),
+        "report" => print!("{}", profiler::report()),
+        _ => println!("usage: profile start|stop|report"),
+    }
+}
+
+fn monitor(_args: &str) {
+    crate::monitor::request();
+}
@@ -0,0 +1,349 @@
+//! GDB remote stub on a serial port
+//!
+//! Boot with `gdb=com2` (and e.g. `-serial stdio -serial tcp::4444,server`
+//! in QEMU), then attach with `target remote localhost:4444`. GDB's first
+//! packet or Ctrl-C stops the kernel with an `int3`; after that the CPU that
+//! trapped talks to GDB with interrupts disabled until it continues. Other
+//! CPUs keep running, one that traps meanwhile waits in its handler until
+//! the first continues, then reports its own stop.
+//!
+//! Supported are the stop reason (`?`), registers (`g`, `G`, `p`, `P`),
+//! memory (`m`, `M`), continuing and single-stepping (`c`, `s`) and
+//! detaching (`D`, `k`). GDB sets breakpoints by writing `int3` into the
+//! code with `M`, which works on read-only kernel text as well.
+//!
+//! The first packet is lost while the stub takes over, GDB sends it again
+//! after its timeout.
+
+pub mod packet;
+pub mod trap;
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use futures_util::stream::StreamExt;
+use x86_64::VirtAddr;
+use crate::serial::{self, ComPort, SerialStream};
+use packet::{Buffer, Transport, PACKET_SIZE};
+pub use trap::TrapFrame;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Set from the first packet until GDB detaches
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Set when GDB asked to stop the running kernel, reported as `SIGINT`
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Index of the CPU talking to GDB, `NO_OWNER` while none is
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+const NO_OWNER: usize = usize::MAX;
+
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::Acquire)
+}
+
+/// Waits for GDB on `port`, then stops the kernel for it
+///
+/// Runs as a task; bytes that arrive while the kernel is stopped are read
+/// by the stub itself.
+pub async fn serve(port: ComPort) {
+    serial::route(SerialStream::Debug, Some(port));
+    log::info!("gdb stub listening on {:?}", port);
+    let mut input = crate::task::serial::debug_stream();
+    while let Some(byte) = input.next().await {
+        if byte != packet::INTERRUPT && byte != b'$' {
+            continue;
+        }
+        if !ATTACHED.swap(true, Ordering::AcqRel) {
+            log::info!("gdb attached");
+        }
+        INTERRUPTED.store(byte == packet::INTERRUPT, Ordering::Release);
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// Talks to GDB until it continues, called by the breakpoint and debug
+/// exception handlers
+///
+/// Returns false without doing anything if no debugger is attached.
+pub(crate) fn handle_trap(frame: &mut TrapFrame) -> bool {
+    if !is_attached() {
+        return false;
+    }
+    // one CPU talks to GDB at a time, the port carries a single session
+    let cpu = crate::smp::current_id();
+    while let Err(owner) = OWNER.compare_exchange(NO_OWNER, cpu, Ordering::AcqRel, Ordering::Acquire) {
+        if owner == cpu {
+            // a trap in the stub itself
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    // GDB may have detached while this CPU waited
+    if !is_attached() {
+        OWNER.store(NO_OWNER, Ordering::Release);
+        return false;
+    }
+    frame.finish_step();
+    let signal = if INTERRUPTED.swap(false, Ordering::AcqRel) { SIGINT } else { SIGTRAP };
+    Stub { transport: DebugPort, frame }.run(signal);
+    OWNER.store(NO_OWNER, Ordering::Release);
+    true
+}
+
+/// The port the `Debug` stream is routed to, polled
+struct DebugPort;
+
+impl Transport for DebugPort {
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            // bytes received before the kernel stopped are queued by the interrupt handler
+            if let Some(byte) = crate::task::serial::try_debug_byte().or_else(|| serial::read_byte(SerialStream::Debug)) {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        serial::write_bytes(SerialStream::Debug, bytes);
+    }
+}
+
+/// Register numbers of GDB's amd64 description: 16 general purpose
+/// registers and `rip` are 8 bytes, `eflags` and the segment registers 4
+const GENERAL_REGISTERS: usize = 17;
+const REGISTERS: usize = 24;
+
+fn register_size(number: usize) -> usize {
+    if number < GENERAL_REGISTERS { 8 } else { 4 }
+}
+
+/// What to do after a command
+enum Reply {
+    Send,
+    Resume,
+    /// Detach, sending the reply first if there is one
+    Detach,
+}
+
+struct Stub<'a, T: Transport> {
+    transport: T,
+    frame: &'a mut TrapFrame,
+}
+
+impl<T: Transport> Stub<'_, T> {
+    fn run(&mut self, signal: u8) {
+        let mut request = Buffer::new();
+        let mut reply = Buffer::new();
+        write!(reply, "S{:02x}", signal).ok();
+        packet::write_packet(&mut self.transport, reply.as_bytes());
+        loop {
+            packet::read_packet(&mut self.transport, &mut request);
+            reply.clear();
+            let action = self.command(request.as_bytes(), &mut reply, signal);
+            if let Reply::Send | Reply::Detach = action {
+                packet::write_packet(&mut self.transport, reply.as_bytes());
+            }
+            match action {
+                Reply::Send => {}
+                Reply::Resume => return,
+                Reply::Detach => {
+                    ATTACHED.store(false, Ordering::Release);
+                    log::info!("gdb detached");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Runs a command, an empty reply tells GDB it is not supported
+    fn command(&mut self, request: &[u8], reply: &mut Buffer, signal: u8) -> Reply {
+        let (command, args) = match request.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Reply::Send,
+        };
+        let ok = match command {
+            b'?' => write!(reply, "S{:02x}", signal).is_ok(),
+            b'g' => {
+                for number in 0..REGISTERS {
+                    let value = self.register(number).unwrap_or(0);
+                    reply.push_hex(&value.to_le_bytes()[..register_size(number)]);
+                }
+                true
+            }
+            b'G' => self.write_registers(args) && write!(reply, "OK").is_ok(),
+            b'p' => match packet::parse_hex(args).map(|number| number as usize).filter(|number| *number < REGISTERS) {
+                Some(number) => {
+                    let value = self.register(number).unwrap_or(0);
+                    reply.push_hex(&value.to_le_bytes()[..register_size(number)])
+                }
+                // not in the description GDB assumes, so it shouldn't ask
+                None => return Reply::Send,
+            },
+            b'P' => self.write_register(args) && write!(reply, "OK").is_ok(),
+            b'm' => read_memory(args, reply),
+            b'M' => write_memory(args) && write!(reply, "OK").is_ok(),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    match packet::parse_hex(args) {
+                        Some(address) => self.frame.rip = address,
+                        None => return error(reply),
+                    }
+                }
+                if command == b's' {
+                    self.frame.begin_step();
+                }
+                return Reply::Resume;
+            }
+            b'D' => {
+                write!(reply, "OK").ok();
+                return Reply::Detach;
+            }
+            b'k' => return Reply::Detach,
+            b'H' => write!(reply, "OK").is_ok(),
+            b'q' if args.starts_with(b"Supported") => write!(reply, "PacketSize={:x}", PACKET_SIZE).is_ok(),
+            b'q' if args == b"Attached" => write!(reply, "1").is_ok(),
+            _ => return Reply::Send,
+        };
+        if !ok {
+            return error(reply);
+        }
+        Reply::Send
+    }
+
+    fn register_mut(&mut self, number: usize) -> Option<&mut u64> {
+        let frame = &mut *self.frame;
+        Some(match number {
+            0 => &mut frame.rax,
+            1 => &mut frame.rbx,
+            2 => &mut frame.rcx,
+            3 => &mut frame.rdx,
+            4 => &mut frame.rsi,
+            5 => &mut frame.rdi,
+            6 => &mut frame.rbp,
+            7 => &mut frame.rsp,
+            8 => &mut frame.r8,
+            9 => &mut frame.r9,
+            10 => &mut frame.r10,
+            11 => &mut frame.r11,
+            12 => &mut frame.r12,
+            13 => &mut frame.r13,
+            14 => &mut frame.r14,
+            15 => &mut frame.r15,
+            16 => &mut frame.rip,
+            17 => &mut frame.rflags,
+            18 => &mut frame.cs,
+            19 => &mut frame.ss,
+            // ds, es, fs and gs aren't saved, they are read as 0
+            _ => return None,
+        })
+    }
+
+    fn register(&mut self, number: usize) -> Option<u64> {
+        self.register_mut(number).map(|value| *value)
+    }
+
+    fn set_register(&mut self, number: usize, bytes: &[u8]) {
+        let mut value = [0; 8];
+        value[..bytes.len()].copy_from_slice(bytes);
+        // the code and stack segments have to stay valid for `iretq`
+        if number != 18 && number != 19 {
+            if let Some(register) = self.register_mut(number) {
+                *register = u64::from_le_bytes(value);
+            }
+        }
+    }
+
+    /// `G` with all registers in hex
+    fn write_registers(&mut self, args: &[u8]) -> bool {
+        let mut offset = 0;
+        for number in 0..REGISTERS {
+            let digits = register_size(number) * 2;
+            let mut bytes = [0; 8];
+            match args.get(offset..offset + digits).and_then(|hex| packet::decode_hex(hex, &mut bytes)) {
+                Some(len) => self.set_register(number, &bytes[..len]),
+                // GDB may send fewer registers than it knows
+                None => break,
+            }
+            offset += digits;
+        }
+        true
+    }
+
+    /// `P` with `number=value`
+    fn write_register(&mut self, args: &[u8]) -> bool {
+        let mut parts = args.splitn(2, |byte| *byte == b'=');
+        let number = match parts.next().and_then(packet::parse_hex) {
+            Some(number) if (number as usize) < REGISTERS => number as usize,
+            _ => return false,
+        };
+        let mut bytes = [0; 8];
+        match parts.next().and_then(|hex| packet::decode_hex(hex, &mut bytes[..register_size(number)])) {
+            Some(len) => {
+                self.set_register(number, &bytes[..len]);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Replies `E14`, EFAULT, for any malformed command or inaccessible memory
+fn error(reply: &mut Buffer) -> Reply {
+    reply.clear();
+    write!(reply, "E14").ok();
+    Reply::Send
+}
+
+/// Splits `address,length` and checks that the memory is mapped
+fn memory_range(args: &[u8]) -> Option<(u64, usize)> {
+    let mut parts = args.splitn(2, |byte| *byte == b',');
+    let address = packet::parse_hex(parts.next()?)?;
+    let len = packet::parse_hex(parts.next()?)? as usize;
+    let end = address.checked_add(len as u64)?;
+    let mut page = address & !0xfff;
+    while page < end {
+        if !crate::memory::is_mapped(VirtAddr::try_new(page).ok()?) {
+            return None;
+        }
+        page += 0x1000;
+    }
+    Some((address, len))
+}
+
+/// `m` with `address,length`, replies with the bytes in hex
+fn read_memory(args: &[u8], reply: &mut Buffer) -> bool {
+    match memory_range(args) {
+        Some((address, len)) if len * 2 <= PACKET_SIZE => {
+            let bytes = unsafe { core::slice::from_raw_parts(address as *const u8, len) };
+            reply.push_hex(bytes)
+        }
+        _ => false,
+    }
+}
+
+/// `M` with `address,length:bytes`, also writes read-only pages like the
+/// kernel's code, so GDB can insert breakpoints
+fn write_memory(args: &[u8]) -> bool {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    let mut parts = args.splitn(2, |byte| *byte == b':');
+    let (address, len) = match parts.next().and_then(memory_range) {
+        Some(range) => range,
+        None => return false,
+    };
+    let mut bytes = [0; PACKET_SIZE / 2];
+    match parts.next().and_then(|hex| packet::decode_hex(hex, &mut bytes)) {
+        Some(decoded) if decoded == len => {}
+        _ => return false,
+    }
+    unsafe {
+        let flags = Cr0::read();
+        Cr0::write(flags - Cr0Flags::WRITE_PROTECT);
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), address as *mut u8, len);
+        Cr0::write(flags);
+    }
+    true
+}
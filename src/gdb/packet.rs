@@ -0,0 +1,182 @@
+//! Framing of the GDB Remote Serial Protocol
+//!
+//! Packets look like `$data#cc`, with `cc` the modulo 256 sum of the data
+//! bytes in hex. The receiver acknowledges each packet with `+`, or asks for
+//! it again with `-`. Everything here works on fixed buffers, as the stub
+//! runs with the rest of the kernel stopped, possibly with the heap locked.
+
+use core::fmt;
+
+/// Largest packet the stub accepts, announced in `qSupported`
+pub const PACKET_SIZE: usize = 1024;
+
+/// Byte GDB sends to interrupt the running kernel
+pub const INTERRUPT: u8 = 0x03;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Byte stream to the debugger
+pub trait Transport {
+    /// Waits for the next byte
+    fn read_byte(&mut self) -> u8;
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+/// Packet data, written with `fmt::Write` or `push_hex`
+pub struct Buffer {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Buffer {
+    pub const fn new() -> Buffer {
+        Buffer { data: [0; PACKET_SIZE], len: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Appends a byte, returns false if the buffer is full
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len == PACKET_SIZE {
+            return false;
+        }
+        self.data[self.len] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Appends the bytes as two lowercase hex digits each
+    pub fn push_hex(&mut self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|byte| self.push(HEX_DIGITS[usize::from(byte >> 4)]) && self.push(HEX_DIGITS[usize::from(byte & 0xf)]))
+    }
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.bytes().all(|byte| self.push(byte)) { Ok(()) } else { Err(fmt::Error) }
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte))
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    char::from(byte).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Parses a hex number like the addresses and lengths in commands
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0, |value, digit| Some(value << 4 | u64::from(hex_digit(*digit)?)))
+}
+
+/// Decodes pairs of hex digits into `out`, returns the number of bytes
+pub fn decode_hex(digits: &[u8], out: &mut [u8]) -> Option<usize> {
+    if digits.len() % 2 != 0 || digits.len() / 2 > out.len() {
+        return None;
+    }
+    for (pair, byte) in digits.chunks(2).zip(out.iter_mut()) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
+
+/// Waits for a packet with a valid checksum and acknowledges it
+///
+/// Bytes outside of packets, like interrupts while the kernel is already
+/// stopped, are skipped.
+pub fn read_packet(transport: &mut impl Transport, packet: &mut Buffer) {
+    loop {
+        while transport.read_byte() != b'$' {}
+        packet.clear();
+        let mut overflow = false;
+        loop {
+            match transport.read_byte() {
+                b'#' => break,
+                byte => overflow |= !packet.push(byte),
+            }
+        }
+        let sum = hex_digit(transport.read_byte()).zip(hex_digit(transport.read_byte()));
+        if !overflow && sum.map(|(high, low)| high << 4 | low) == Some(checksum(packet.as_bytes())) {
+            transport.write_bytes(b"+");
+            return;
+        }
+        transport.write_bytes(b"-");
+    }
+}
+
+/// Sends a packet until the debugger acknowledges it
+pub fn write_packet(transport: &mut impl Transport, data: &[u8]) {
+    let sum = checksum(data);
+    let trailer = [b'#', HEX_DIGITS[usize::from(sum >> 4)], HEX_DIGITS[usize::from(sum & 0xf)]];
+    loop {
+        transport.write_bytes(b"$");
+        transport.write_bytes(data);
+        transport.write_bytes(&trailer);
+        loop {
+            match transport.read_byte() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+struct TestTransport {
+    input: alloc::collections::VecDeque<u8>,
+    output: alloc::vec::Vec<u8>,
+}
+
+#[cfg(test)]
+impl Transport for TestTransport {
+    fn read_byte(&mut self) -> u8 {
+        self.input.pop_front().expect("test input exhausted")
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+}
+
+#[test_case]
+fn test_hex() {
+    let mut bytes = [0; 4];
+    assert_eq!(parse_hex(b"ffff8000"), Some(0xffff_8000));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"12g"), None);
+    assert_eq!(decode_hex(b"dead00", &mut bytes), Some(3));
+    assert_eq!(bytes[..3], [0xde, 0xad, 0x00]);
+    assert_eq!(decode_hex(b"abc", &mut bytes), None);
+
+    let mut buffer = Buffer::new();
+    buffer.push_hex(&[0x01, 0xab]);
+    assert_eq!(buffer.as_bytes(), b"01ab");
+}
+
+#[test_case]
+fn test_packets() {
+    let mut transport = TestTransport {
+        // a corrupted packet, its resend, and the ack of the reply
+        input: b"\x03$g#00$g#67+".iter().copied().collect(),
+        output: alloc::vec::Vec::new(),
+    };
+    let mut packet = Buffer::new();
+    read_packet(&mut transport, &mut packet);
+    assert_eq!(packet.as_bytes(), b"g");
+    assert_eq!(transport.output, b"-+");
+
+    transport.output.clear();
+    write_packet(&mut transport, b"OK");
+    assert_eq!(transport.output, b"$OK#9a");
+}
@@ -0,0 +1,142 @@
+//! Entry of the debug and breakpoint exceptions with all registers saved
+//!
+//! `x86-interrupt` handlers only see the interrupt stack frame, but the
+//! debugger has to read and change every register of the interrupted code.
+//! These entries push them into a `TrapFrame`, call the handler in
+//! `interrupts` with it and return with whatever the handler left there.
+
+use core::arch::global_asm;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::smp::percpu;
+
+/// Debug exception, raised after an instruction if the trap flag is set
+pub const DEBUG_VECTOR: u64 = 1;
+/// Breakpoint exception, raised by `int3`
+pub const BREAKPOINT_VECTOR: u64 = 3;
+
+/// Registers of the interrupted code, in the order they are on the stack
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Always 0, pushed so all entries share the frame layout
+    pub error_code: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Sets the trap flag, so the code stops again after one instruction
+    ///
+    /// Interrupts are kept off for the step, or it would land in the next
+    /// timer interrupt. `finish_step` turns them back on.
+    pub fn begin_step(&mut self) {
+        let interrupts = RFlags::INTERRUPT_FLAG.bits();
+        percpu::current().set_step_masked_interrupts(self.rflags & interrupts != 0);
+        self.rflags = (self.rflags & !interrupts) | RFlags::TRAP_FLAG.bits();
+    }
+
+    /// Clears the trap flag and restores what `begin_step` changed on this CPU
+    pub fn finish_step(&mut self) {
+        self.rflags &= !RFlags::TRAP_FLAG.bits();
+        if percpu::current().take_step_masked_interrupts() {
+            self.rflags |= RFlags::INTERRUPT_FLAG.bits();
+        }
+    }
+}
+
+// The CPU aligns the stack before pushing its 5 words; with the error code,
+// vector and 15 registers the frame is 176 bytes, so `call` is aligned too
+global_asm!(
+    ".global debug_entry",
+    "debug_entry:",
+    "push 0",
+    "push {debug}",
+    "jmp trap_common",
+    ".global breakpoint_entry",
+    "breakpoint_entry:",
+    "push 0",
+    "push {breakpoint}",
+    "jmp trap_common",
+    "trap_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16",
+    "iretq",
+    debug = const DEBUG_VECTOR,
+    breakpoint = const BREAKPOINT_VECTOR,
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn debug_entry();
+    fn breakpoint_entry();
+}
+
+/// Address for the debug exception's IDT entry
+pub fn debug_entry_addr() -> VirtAddr {
+    VirtAddr::new(debug_entry as u64)
+}
+
+/// Address for the breakpoint exception's IDT entry
+pub fn breakpoint_entry_addr() -> VirtAddr {
+    VirtAddr::new(breakpoint_entry as u64)
+}
+
+extern "C" fn dispatch(frame: &mut TrapFrame) {
+    match frame.vector {
+        DEBUG_VECTOR => crate::interrupts::debug_handler(frame),
+        _ => crate::interrupts::breakpoint_handler(frame),
+    }
+}
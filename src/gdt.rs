@@ -5,51 +5,132 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    #[derive(Debug)]
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
+/// Stack used when an interrupt or exception arrives while running in ring 3,
+/// until `set_kernel_stack` points it at the stack of the running thread
+const PRIVILEGE_STACK_SIZE: usize = 4096 * 4;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
+
+/// TSS of the bootstrap processor, the other CPUs allocate theirs in `init_ap`
+///
+/// Mutable because the ring 0 stack changes with the running thread,
+/// see `set_kernel_stack`
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Sets up the stacks in the bootstrap processor's TSS, called once when the GDT is created
+fn init_tss() -> &'static TaskStateSegment {
+    let tss = unsafe { &mut *core::ptr::addr_of_mut!(TSS) };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
+        stack_end
     };
+    tss.privilege_stack_table[0] = {
+        static mut STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + PRIVILEGE_STACK_SIZE;
+        stack_end
+    };
+    log::trace!("TSS: {:?}", tss);
+    tss
+}
+
+/// Builds a GDT around `tss`, every CPU has the same layout
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // `sysret` expects user data right before user code,
+    // `syscall` expects kernel data right after kernel code
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        },
+    )
 }
 
 lazy_static! {
     #[derive(Debug)]
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(init_tss());
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+/// Returns the segment selectors, which are the same on every CPU
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// Sets the stack the CPU switches to when entering ring 0 from ring 3
+///
+/// Only affects the calling CPU.
+///
+/// This function is unsafe because the caller must guarantee that `stack_end`
+/// is the end of a valid stack that is not in use.
+pub unsafe fn set_kernel_stack(stack_end: VirtAddr) {
+    crate::smp::percpu::current().set_kernel_stack(stack_end);
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    log::trace!("loading GDT: {:?}", GDT);
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        SS::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
+
+/// Loads the GDT and TSS of the bootstrap processor
+///
+/// Must be called after the per-CPU data was installed.
+pub fn init() {
+    log::trace!("loading GDT: {:?}", GDT);
+    load(&GDT);
+    let cpu = crate::smp::percpu::current();
+    let tss = unsafe { &mut *core::ptr::addr_of_mut!(TSS) };
+    cpu.set_tss(tss);
+    unsafe { cpu.set_kernel_stack(tss.privilege_stack_table[0]) };
+}
+
+/// Allocates and loads a GDT and TSS for an application processor
+///
+/// Must be called on that processor, after its per-CPU data was installed.
+pub fn init_ap() {
+    fn stack(size: usize) -> VirtAddr {
+        let stack = alloc::vec![0u8; size].leak();
+        VirtAddr::from_ptr(stack.as_ptr()) + size
+    }
+
+    let tss = alloc::boxed::Box::leak(alloc::boxed::Box::new(TaskStateSegment::new()));
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack(DOUBLE_FAULT_STACK_SIZE);
+    let privilege_stack = stack(PRIVILEGE_STACK_SIZE);
+    tss.privilege_stack_table[0] = privilege_stack;
+    let tss_ptr: *mut TaskStateSegment = tss;
+
+    let gdt = alloc::boxed::Box::leak(alloc::boxed::Box::new(new_gdt(unsafe { &*tss_ptr })));
+    load(gdt);
+    let cpu = crate::smp::percpu::current();
+    cpu.set_tss(tss_ptr);
+    unsafe { cpu.set_kernel_stack(privilege_stack) };
+}
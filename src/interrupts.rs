@@ -1,9 +1,12 @@
 use crate::gdt;
-use crate::println;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use crate::gdb::TrapFrame;
 
 use crate::serial_println;
 
@@ -18,11 +21,31 @@ pub static PICS: spin::Mutex<ChainedPics> =
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// COM2 and COM4, IRQ 3
+    Serial2 = PIC_1_OFFSET + 3,
+    /// COM1 and COM3, IRQ 4
+    Serial1,
     Mouse = PIC_1_OFFSET + 12,
+    /// IRQ 14, see `ata`
+    PrimaryAta = PIC_1_OFFSET + 14,
+    SecondaryAta,
+    /// Sent between CPUs to wake one from `hlt`, see `smp::kick`
+    Wakeup = 0xf0,
+    Spurious = 0xff,
 }
 
+/// First of the vectors handed out by `allocate_msi_vector`
+pub const MSI_VECTOR_BASE: u8 = 0x50;
+const MSI_VECTOR_COUNT: usize = 8;
+
+/// Handler of each MSI vector as a `fn()` address, 0 while the vector is free
+static MSI_HANDLERS: [AtomicUsize; MSI_VECTOR_COUNT] = {
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    [FREE; MSI_VECTOR_COUNT]
+};
+
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -36,7 +59,11 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // both save all registers for the debugger, see `gdb::trap`
+        unsafe {
+            idt.debug.set_handler_addr(crate::gdb::trap::debug_entry_addr());
+            idt.breakpoint.set_handler_addr(crate::gdb::trap::breakpoint_entry_addr());
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
 
         unsafe {
@@ -47,7 +74,16 @@ lazy_static! {
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial2.as_usize()].set_handler_fn(serial2_interrupt_handler);
+        idt[InterruptIndex::Serial1.as_usize()].set_handler_fn(serial1_interrupt_handler);
         idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(secondary_ata_interrupt_handler);
+        idt[InterruptIndex::Wakeup.as_usize()].set_handler_fn(wakeup_interrupt_handler);
+        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
+        for (index, handler) in MSI_ENTRY_POINTS.iter().enumerate() {
+            idt[usize::from(MSI_VECTOR_BASE) + index].set_handler_fn(*handler);
+        }
         idt
     };
 }
@@ -58,13 +94,25 @@ pub fn init_idt() {
 }
 
 #[cfg(not(test))]
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+pub(crate) fn breakpoint_handler(frame: &mut TrapFrame) {
+    let requested = crate::monitor::take_request();
+    if !crate::gdb::handle_trap(frame) {
+        crate::monitor::breakpoint(frame, requested);
+    }
 }
 
 #[cfg(test)]
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+pub(crate) fn breakpoint_handler(frame: &mut TrapFrame) {
+    serial_println!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
+}
+
+/// Raised after each instruction while the trap flag is set
+pub(crate) fn debug_handler(frame: &mut TrapFrame) {
+    if crate::gdb::handle_trap(frame) || crate::monitor::debug(frame) {
+        return;
+    }
+    log::warn!("unexpected debug exception at {}", crate::backtrace::Frame::new(VirtAddr::new(frame.rip)));
+    frame.rflags &= !RFlags::TRAP_FLAG.bits();
 }
 
 #[test_case]
@@ -79,10 +127,16 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        log::warn!("process page fault at {:?} ({:?}), ip {:?}", Cr2::read(), error_code, stack_frame.instruction_pointer);
+        crate::process::kill();
+    }
+
     serial_println!("EXCEPTION: PAGE FAULT");
     serial_println!("Accessed Address: {:?}", Cr2::read());
     serial_println!("Error Code: {:?}", error_code);
     serial_println!("{:#?}", stack_frame);
+    serial_println!("at {}", crate::backtrace::Frame::new(stack_frame.instruction_pointer));
     //hlt_loop();
     panic!("Unhandled page fault");
 }
@@ -91,15 +145,21 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    panic!(
+        "EXCEPTION: DOUBLE FAULT\n{:#?}\nat {}",
+        stack_frame,
+        crate::backtrace::Frame::new(stack_frame.instruction_pointer)
+    );
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    crate::profiler::sample(&stack_frame);
     crate::time::increment_time();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    crate::thread::preempt();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -115,6 +175,24 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+extern "x86-interrupt" fn serial1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::receive_interrupt(4);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial1.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn serial2_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::receive_interrupt(3);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial2.as_u8());
+    }
+}
+
 extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::PortReadOnly;
 
@@ -127,3 +205,73 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFr
             .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
     }
 }
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::ata::interrupt(0);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::ata::interrupt(1);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // nothing to do, returning from `hlt` is the point
+    crate::smp::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // spurious interrupts must not be acknowledged
+}
+
+/// Reserves a vector for a message signalled interrupt, see `pci::msi`
+///
+/// `handler` runs in interrupt context on the bootstrap processor, the end
+/// of interrupt is signalled after it returns. Returns `None` if all MSI
+/// vectors are taken.
+pub fn allocate_msi_vector(handler: fn()) -> Option<u8> {
+    MSI_HANDLERS.iter()
+        .position(|slot| slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire).is_ok())
+        .map(|index| MSI_VECTOR_BASE + index as u8)
+}
+
+fn msi_interrupt(index: usize) {
+    let handler = MSI_HANDLERS[index].load(Ordering::Acquire);
+    if handler != 0 {
+        // only `allocate_msi_vector` stores into the slots, always a `fn()`
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    crate::smp::apic::end_of_interrupt();
+}
+
+macro_rules! msi_handlers {
+    ($($index:literal => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                msi_interrupt($index);
+            }
+        )*
+        const MSI_ENTRY_POINTS: [extern "x86-interrupt" fn(InterruptStackFrame); MSI_VECTOR_COUNT] = [$($name),*];
+    };
+}
+
+msi_handlers!(
+    0 => msi_handler_0,
+    1 => msi_handler_1,
+    2 => msi_handler_2,
+    3 => msi_handler_3,
+    4 => msi_handler_4,
+    5 => msi_handler_5,
+    6 => msi_handler_6,
+    7 => msi_handler_7
+);
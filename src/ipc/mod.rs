@@ -0,0 +1,71 @@
+//! Message passing between tasks
+//!
+//! `port` provides named mailboxes carrying tagged messages, `pipe` bounded
+//! byte streams. Both apply backpressure: senders and writers wait while the
+//! receiving side is full, instead of buffering without limit.
+
+pub mod port;
+pub mod pipe;
+
+pub use port::{Port, PortHandle, Message, connect};
+pub use pipe::{pipe, PipeReader, PipeWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    /// Another port is already bound under the name
+    NameInUse,
+    NoSuchPort,
+    /// The receiving side is gone
+    Closed,
+    /// Returned by the non-blocking operations instead of waiting
+    Full,
+}
+
+#[test_case]
+fn test_pipe_backpressure() {
+    use crate::task::{Task, simple_executor::SimpleExecutor};
+    use crate::sync::Mutex;
+    use alloc::{sync::Arc, vec::Vec};
+
+    let (writer, mut reader) = pipe(4);
+    assert_eq!(writer.try_write(b"abcdef"), Ok(4));
+    assert_eq!(writer.try_write(b"ef"), Err(IpcError::Full));
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        writer.write_all(b"efghij").await.expect("reader dropped");
+    }));
+    executor.spawn(Task::new({
+        let received = received.clone();
+        async move {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await;
+            *received.lock().await = data;
+        }
+    }));
+    executor.run();
+    assert_eq!(received.try_lock().map(|data| data.clone()), Some(b"abcdefghij".to_vec()));
+}
+
+#[test_case]
+fn test_port_request() {
+    use crate::task::{Task, simple_executor::SimpleExecutor};
+
+    let mut port = Port::bind("test.echo", 1).expect("name in use");
+    assert_eq!(Port::bind("test.echo", 1).err(), Some(IpcError::NameInUse));
+
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        let message = port.recv().await;
+        let reply = Message::new(message.tag + 1, message.data);
+        message.reply_to.expect("no reply port").send(reply).await.expect("requester gone");
+    }));
+    executor.spawn(Task::new(async {
+        let handle = connect("test.echo").expect("port not bound");
+        let reply = handle.request(Message::new(1, "ping")).await.expect("no reply");
+        assert_eq!((reply.tag, &reply.data[..]), (2, &b"ping"[..]));
+    }));
+    executor.run();
+    assert_eq!(connect("test.echo").err(), Some(IpcError::NoSuchPort));
+}
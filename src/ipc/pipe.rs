@@ -0,0 +1,227 @@
+//! Bounded byte pipe
+//!
+//! Writers wait while the pipe is full, so a slow reader applies
+//! backpressure. Reading returns 0 once all writers are gone and the
+//! buffered bytes are consumed, like the end of a file.
+
+use super::IpcError;
+use crate::sync::{IrqLock, Waiter, WaitQueue};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll, Waker};
+use futures_util::future::poll_fn;
+
+struct State {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    writers: usize,
+    reader_closed: bool,
+    reader_waker: Option<Waker>,
+    /// Writers waiting for free space
+    writer_waiters: WaitQueue,
+}
+
+impl State {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+    assert!(capacity > 0, "pipe capacity must be at least 1");
+    let state = Arc::new(IrqLock::new(State {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        writers: 1,
+        reader_closed: false,
+        reader_waker: None,
+        writer_waiters: WaitQueue::new(),
+    }));
+    (PipeWriter { state: state.clone() }, PipeReader { state })
+}
+
+pub struct PipeWriter {
+    state: Arc<IrqLock<State>>,
+}
+
+impl PipeWriter {
+    /// Writes as many bytes as fit, waiting while the pipe is full
+    ///
+    /// Returns the number of bytes written, which is only 0 for an empty `buf`.
+    pub async fn write(&self, buf: &[u8]) -> Result<usize, IpcError> {
+        Write { writer: self, buf, waiter: None }.await
+    }
+
+    /// Writes all of `buf`, waiting for the reader as often as needed
+    pub async fn write_all(&self, mut buf: &[u8]) -> Result<(), IpcError> {
+        while !buf.is_empty() {
+            let written = self.write(buf).await?;
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    /// Writes as many bytes as fit without waiting
+    ///
+    /// Can be called from interrupt handlers, the buffer is allocated for
+    /// `capacity` bytes up front.
+    pub fn try_write(&self, buf: &[u8]) -> Result<usize, IpcError> {
+        self.state.with(|state| Self::write_locked(state, buf).unwrap_or(Err(IpcError::Full)))
+    }
+
+    fn write_locked(state: &mut State, buf: &[u8]) -> Option<Result<usize, IpcError>> {
+        if state.reader_closed {
+            return Some(Err(IpcError::Closed));
+        }
+        let len = buf.len().min(state.capacity - state.buffer.len());
+        if len == 0 && !buf.is_empty() {
+            return None;
+        }
+        state.buffer.extend(&buf[..len]);
+        state.wake_reader();
+        Some(Ok(len))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.with(|state| state.reader_closed)
+    }
+}
+
+/// Future of `PipeWriter::write`, leaves the wait queue when dropped
+struct Write<'a> {
+    writer: &'a PipeWriter,
+    buf: &'a [u8],
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Future for Write<'_> {
+    type Output = Result<usize, IpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(waiter) = &this.waiter {
+            waiter.register(cx.waker());
+            if !waiter.is_notified() {
+                return Poll::Pending;
+            }
+            this.waiter = None;
+        }
+
+        let (writer, buf) = (this.writer, this.buf);
+        writer.state.with(|state| match PipeWriter::write_locked(state, buf) {
+            Some(result) => Poll::Ready(result),
+            None => {
+                let waiter = Waiter::new(cx.waker());
+                state.writer_waiters.push(waiter.clone());
+                this.waiter = Some(waiter);
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for Write<'_> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            self.writer.state.with(|state| state.writer_waiters.remove(&waiter));
+        }
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.state.with(|state| state.writers += 1);
+        PipeWriter { state: self.state.clone() }
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.state.with(|state| {
+            state.writers -= 1;
+            if state.writers == 0 {
+                state.wake_reader();
+            }
+        });
+    }
+}
+
+pub struct PipeReader {
+    state: Arc<IrqLock<State>>,
+}
+
+impl PipeReader {
+    /// Reads the buffered bytes into `buf`, waiting until there are some
+    ///
+    /// Returns 0 once all writers are gone and the pipe is empty.
+    pub async fn read(&mut self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    /// Reads until all writers are gone
+    pub async fn read_to_end(&mut self, out: &mut Vec<u8>) -> usize {
+        let mut buf = [0; 64];
+        let mut total = 0;
+        loop {
+            let len = self.read(&mut buf).await;
+            if len == 0 {
+                return total;
+            }
+            out.extend_from_slice(&buf[..len]);
+            total += len;
+        }
+    }
+
+    /// Reads the buffered bytes without waiting, `None` if there are none
+    /// but a writer may still write some
+    pub fn try_read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        self.state.with(|state| Self::read_locked(state, buf))
+    }
+
+    fn read_locked(state: &mut State, buf: &mut [u8]) -> Option<usize> {
+        if state.buffer.is_empty() {
+            return if state.writers == 0 || buf.is_empty() { Some(0) } else { None };
+        }
+        let len = buf.len().min(state.buffer.len());
+        for (dest, byte) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+            *dest = byte;
+        }
+        state.writer_waiters.notify_all();
+        Some(len)
+    }
+
+    pub fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize> {
+        self.state.with(|state| match Self::read_locked(state, buf) {
+            Some(len) => Poll::Ready(len),
+            None => {
+                state.reader_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Number of bytes waiting to be read
+    pub fn len(&self) -> usize {
+        self.state.with(|state| state.buffer.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stops accepting bytes, writers fail from now on
+    pub fn close(&mut self) {
+        self.state.with(|state| {
+            state.reader_closed = true;
+            state.writer_waiters.notify_all();
+        });
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,157 @@
+//! Named ports, mailboxes that other tasks find by name
+//!
+//! A task binds a `Port` under a name and receives the messages sent to it,
+//! others `connect` to the name and get a `PortHandle` to send with. Queued
+//! messages are bounded by the capacity given to `bind`, senders wait while
+//! the port is full. The name is released when the `Port` is dropped.
+
+use super::IpcError;
+use crate::sync::mpsc::{self, SendError, TrySendError};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+lazy_static! {
+    static ref PORTS: Mutex<BTreeMap<String, mpsc::Sender<Message>>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug)]
+pub struct Message {
+    /// Meaning of the message, defined by the port's protocol
+    pub tag: u64,
+    pub data: Vec<u8>,
+    /// Where the receiver should send its answer, if any
+    pub reply_to: Option<PortHandle>,
+}
+
+impl Message {
+    pub fn new(tag: u64, data: impl Into<Vec<u8>>) -> Message {
+        Message {
+            tag,
+            data: data.into(),
+            reply_to: None,
+        }
+    }
+
+    pub fn with_reply_to(mut self, port: PortHandle) -> Message {
+        self.reply_to = Some(port);
+        self
+    }
+}
+
+/// The receiving end of a port
+pub struct Port {
+    /// `None` for anonymous ports
+    name: Option<String>,
+    receiver: mpsc::Receiver<Message>,
+    sender: mpsc::Sender<Message>,
+}
+
+impl Port {
+    /// Creates a port that others can `connect` to under `name`
+    pub fn bind(name: &str, capacity: usize) -> Result<Port, IpcError> {
+        let mut ports = PORTS.lock();
+        if ports.contains_key(name) {
+            return Err(IpcError::NameInUse);
+        }
+        let mut port = Port::anonymous(capacity);
+        ports.insert(name.into(), port.sender.clone());
+        port.name = Some(name.into());
+        Ok(port)
+    }
+
+    /// Creates a port without a name, reachable only through its handles,
+    /// e.g. for replies
+    pub fn anonymous(capacity: usize) -> Port {
+        let (sender, receiver) = mpsc::channel(capacity);
+        Port {
+            name: None,
+            receiver,
+            sender,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns a handle to send messages to this port
+    pub fn handle(&self) -> PortHandle {
+        PortHandle {
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Waits for the next message
+    pub async fn recv(&mut self) -> Message {
+        // the port holds a sender itself, so the channel never runs dry
+        self.receiver.recv().await.expect("port has its own sender")
+    }
+
+    pub fn try_recv(&mut self) -> Option<Message> {
+        self.receiver.try_recv()
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        if let Some(name) = &self.name {
+            PORTS.lock().remove(name);
+        }
+    }
+}
+
+/// The sending end of a port, cheap to clone
+#[derive(Clone)]
+pub struct PortHandle {
+    sender: mpsc::Sender<Message>,
+}
+
+impl PortHandle {
+    /// Sends a message, waiting while the port is full
+    pub async fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        self.sender.send(message).await
+    }
+
+    /// Sends a message if the port has room
+    ///
+    /// Can be called from interrupt handlers, see `mpsc::Sender::try_send`.
+    pub fn try_send(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        self.sender.try_send(message)
+    }
+
+    /// Sends a message and waits for the answer sent to its `reply_to`
+    ///
+    /// Fails if the receiver drops the message without answering.
+    pub async fn request(&self, message: Message) -> Result<Message, IpcError> {
+        // unlike a `Port`, no sender is kept here, so a dropped `reply_to` ends the wait
+        let (sender, mut receiver) = mpsc::channel(1);
+        self.send(message.with_reply_to(PortHandle { sender })).await.map_err(|_| IpcError::Closed)?;
+        receiver.recv().await.ok_or(IpcError::Closed)
+    }
+
+    /// Returns true if the port was dropped
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+impl core::fmt::Debug for PortHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("PortHandle").field("closed", &self.is_closed()).finish()
+    }
+}
+
+/// Returns a handle to the port bound under `name`
+pub fn connect(name: &str) -> Result<PortHandle, IpcError> {
+    PORTS
+        .lock()
+        .get(name)
+        .map(|sender| PortHandle { sender: sender.clone() })
+        .ok_or(IpcError::NoSuchPort)
+}
+
+/// Names of all bound ports
+pub fn names() -> Vec<String> {
+    PORTS.lock().keys().cloned().collect()
+}
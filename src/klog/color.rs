@@ -0,0 +1,81 @@
+//! Level colors of the serial and KernelLog terminal output
+//!
+//! The serial output uses ANSI escape sequences, which can be turned off
+//! for consumers that don't understand them with `set_ansi` or the
+//! `logcolor=off` boot option.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::Level;
+use vga::colors::{Color16, TextModeColor};
+
+static ANSI: AtomicBool = AtomicBool::new(true);
+
+const ANSI_RESET: &str = "\x1b[0m";
+/// Faint, for the source column
+const ANSI_SOURCE: &str = "\x1b[2m";
+
+pub fn set_ansi(enabled: bool) {
+    ANSI.store(enabled, Ordering::Relaxed);
+}
+
+pub fn ansi_enabled() -> bool {
+    ANSI.load(Ordering::Relaxed)
+}
+
+fn ansi_level(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[1;31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[36m",
+        Level::Trace => "\x1b[90m",
+    }
+}
+
+/// Text colored for a serial terminal, if ANSI colors are enabled
+pub struct Ansi<T> {
+    style: &'static str,
+    value: T,
+}
+
+impl<T: fmt::Display> fmt::Display for Ansi<T> {
+    /// Padding and alignment apply to `value`, not to the escape sequences
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if ansi_enabled() {
+            f.write_str(self.style)?;
+            self.value.fmt(f)?;
+            f.write_str(ANSI_RESET)
+        } else {
+            self.value.fmt(f)
+        }
+    }
+}
+
+pub fn level<T>(level: Level, value: T) -> Ansi<T> {
+    Ansi { style: ansi_level(level), value }
+}
+
+pub fn source<T>(value: T) -> Ansi<T> {
+    Ansi { style: ANSI_SOURCE, value }
+}
+
+fn on_background(foreground: Color16) -> TextModeColor {
+    TextModeColor::new(foreground, Color16::Blue)
+}
+
+/// Color of the level column in the KernelLog terminal
+pub fn vga_level(level: Level) -> TextModeColor {
+    on_background(match level {
+        Level::Error => Color16::LightRed,
+        Level::Warn => Color16::Yellow,
+        Level::Info => Color16::LightGreen,
+        Level::Debug => Color16::White,
+        Level::Trace => Color16::LightGrey,
+    })
+}
+
+/// Color of the source column in the KernelLog terminal
+pub fn vga_source() -> TextModeColor {
+    on_background(Color16::LightCyan)
+}
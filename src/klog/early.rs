@@ -0,0 +1,147 @@
+//! Lock-free log buffer for records that can't go to the ring buffer yet
+//!
+//! Used before the heap exists and while interrupts are disabled, e.g. in
+//! interrupt handlers, where taking the ring buffer's lock could deadlock
+//! and allocating is not allowed. Records are copied into fixed slots
+//! claimed with atomics, and moved to the ring buffer by `drain` the next
+//! time it is safe. Records that find no free slot are counted as dropped.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use log::{Level, Record};
+
+const SLOTS: usize = 64;
+const MESSAGE_LEN: usize = 128;
+
+const FREE: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+struct EarlyRecord {
+    /// Order the slots were claimed in
+    stamp: usize,
+    level: Level,
+    module_path: Option<&'static str>,
+    file: Option<&'static str>,
+    line: Option<u32>,
+    time: usize,
+    message: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+struct Slot {
+    state: AtomicU8,
+    record: UnsafeCell<EarlyRecord>,
+}
+
+// a slot's record is only accessed by whoever moved its state away from FREE
+unsafe impl Sync for Slot {}
+
+static SLOT_BUFFER: [Slot; SLOTS] = {
+    const EMPTY: Slot = Slot {
+        state: AtomicU8::new(FREE),
+        record: UnsafeCell::new(EarlyRecord {
+            stamp: 0,
+            level: Level::Trace,
+            module_path: None,
+            file: None,
+            line: None,
+            time: 0,
+            message: [0; MESSAGE_LEN],
+            len: 0,
+        }),
+    };
+    [EMPTY; SLOTS]
+};
+
+static NEXT_STAMP: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Appends to a fixed buffer, cutting off what doesn't fit
+struct FixedWriter<'a> {
+    buffer: &'a mut [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for FixedWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            let mut encoded = [0; 4];
+            let encoded = character.encode_utf8(&mut encoded).as_bytes();
+            if self.len + encoded.len() > MESSAGE_LEN {
+                return Err(fmt::Error);
+            }
+            self.buffer[self.len..self.len + encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
+        }
+        Ok(())
+    }
+}
+
+/// Stores a record without locking or allocating
+pub fn push(record: &Record, time: usize) {
+    let stamp = NEXT_STAMP.fetch_add(1, Ordering::Relaxed);
+    // any free slot will do, the stamp restores the order
+    let slot = (0..SLOTS)
+        .map(|offset| &SLOT_BUFFER[(stamp + offset) % SLOTS])
+        .find(|slot| slot.state.compare_exchange(FREE, WRITING, Ordering::Acquire, Ordering::Relaxed).is_ok());
+    let slot = match slot {
+        Some(slot) => slot,
+        None => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    let early = unsafe { &mut *slot.record.get() };
+    early.stamp = stamp;
+    early.level = record.level();
+    early.module_path = record.module_path_static();
+    early.file = record.file_static();
+    early.line = record.line();
+    early.time = time;
+    let mut writer = FixedWriter { buffer: &mut early.message, len: 0 };
+    write!(writer, "{}", record.args()).ok();
+    early.len = writer.len;
+    slot.state.store(READY, Ordering::Release);
+}
+
+/// Calls `f` with the stored records in the order they were logged, and frees their slots
+///
+/// Records that are still being written stay for the next call.
+pub fn drain(mut f: impl FnMut(&Record, usize)) {
+    let mut ready = [0usize; SLOTS];
+    let mut count = 0;
+    for (index, slot) in SLOT_BUFFER.iter().enumerate() {
+        if slot.state.load(Ordering::Acquire) == READY {
+            ready[count] = index;
+            count += 1;
+        }
+    }
+    let ready = &mut ready[..count];
+    ready.sort_unstable_by_key(|index| unsafe { (*SLOT_BUFFER[*index].record.get()).stamp });
+
+    for index in ready.iter() {
+        let slot = &SLOT_BUFFER[*index];
+        let early = unsafe { &*slot.record.get() };
+        let message = core::str::from_utf8(&early.message[..early.len]).unwrap_or("<invalid message>");
+        f(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(early.level)
+                .target(early.module_path.unwrap_or("unknown"))
+                .module_path_static(early.module_path)
+                .file_static(early.file)
+                .line(early.line)
+                .build(),
+            early.time,
+        );
+        slot.state.store(FREE, Ordering::Release);
+    }
+}
+
+/// Records lost because all slots were in use
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,158 @@
+//! Per-target log level filter
+//!
+//! Directives are written like `env_logger`'s: a comma separated list of
+//! `target=level` entries and at most one bare `level` for everything else,
+//! e.g. `info,rust_stuff::peripheral::mouse=trace,rust_stuff::gdt=off`.
+//! A directive applies to all targets starting with its target (targets are
+//! module paths by default), the longest matching one wins.
+//!
+//! The filter is a fixed size array that holds copies of the targets, so it
+//! can be built and checked without the heap.
+
+use core::fmt;
+use log::{LevelFilter, Metadata};
+
+/// Directives beyond this are rejected
+pub const MAX_DIRECTIVES: usize = 16;
+/// Longest target a directive can hold, in bytes
+pub const MAX_TARGET_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    BadLevel,
+    EmptyTarget,
+    TargetTooLong,
+    TooManyDirectives,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Directive {
+    target: [u8; MAX_TARGET_LEN],
+    target_len: u8,
+    level: LevelFilter,
+}
+
+impl Directive {
+    fn target(&self) -> &str {
+        // copied from a `&str` by `Filter::set`
+        core::str::from_utf8(&self.target[..usize::from(self.target_len)]).unwrap_or("")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Filter {
+    default: LevelFilter,
+    directives: [Option<Directive>; MAX_DIRECTIVES],
+}
+
+impl Filter {
+    /// A filter with only a default level
+    pub const fn new(default: LevelFilter) -> Filter {
+        Filter {
+            default,
+            directives: [None; MAX_DIRECTIVES],
+        }
+    }
+
+    /// Parses directives, targets without a directive use `default`
+    /// unless the directives contain a bare level
+    pub fn parse(spec: &str, default: LevelFilter) -> Result<Filter, FilterError> {
+        let mut filter = Filter::new(default);
+        for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.find('=') {
+                Some(index) => {
+                    let level = directive[index + 1..].trim().parse().map_err(|_| FilterError::BadLevel)?;
+                    filter.set(directive[..index].trim(), level)?;
+                }
+                None => filter.default = directive.parse().map_err(|_| FilterError::BadLevel)?,
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    pub fn set_default(&mut self, level: LevelFilter) {
+        self.default = level;
+    }
+
+    /// Sets the level of a target, replacing its previous directive
+    pub fn set(&mut self, target: &str, level: LevelFilter) -> Result<(), FilterError> {
+        if target.is_empty() {
+            return Err(FilterError::EmptyTarget);
+        }
+        if target.len() > MAX_TARGET_LEN {
+            return Err(FilterError::TargetTooLong);
+        }
+        let slot = match self.directives.iter().position(|d| matches!(d, Some(d) if d.target() == target)) {
+            Some(index) => index,
+            None => self.directives.iter().position(Option::is_none).ok_or(FilterError::TooManyDirectives)?,
+        };
+        let mut directive = Directive { target: [0; MAX_TARGET_LEN], target_len: target.len() as u8, level };
+        directive.target[..target.len()].copy_from_slice(target.as_bytes());
+        self.directives[slot] = Some(directive);
+        Ok(())
+    }
+
+    /// Level enabled for `target`, from the longest matching directive
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .filter(|directive| target.starts_with(directive.target()))
+            .max_by_key(|directive| directive.target_len)
+            .map_or(self.default, |directive| directive.level)
+    }
+
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    /// Most verbose level of any target, for `log::set_max_level`
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives.iter().flatten().map(|directive| directive.level).fold(self.default, LevelFilter::max)
+    }
+}
+
+impl fmt::Display for Filter {
+    /// Formats the filter as directives that `parse` accepts
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", level_name(self.default))?;
+        for directive in self.directives.iter().flatten() {
+            write!(f, ",{}={}", directive.target(), level_name(directive.level))?;
+        }
+        Ok(())
+    }
+}
+
+fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+#[test_case]
+fn test_filter_directives() {
+    let filter = Filter::parse("warn, rust_stuff::peripheral=debug,rust_stuff::peripheral::mouse=trace", LevelFilter::Info)
+        .expect("valid directives");
+    assert_eq!(filter.level_for("rust_stuff::gdt"), LevelFilter::Warn);
+    assert_eq!(filter.level_for("rust_stuff::peripheral::keyboard"), LevelFilter::Debug);
+    assert_eq!(filter.level_for("rust_stuff::peripheral::mouse"), LevelFilter::Trace);
+    assert_eq!(filter.max_level(), LevelFilter::Trace);
+    assert_eq!(Filter::parse("rust_stuff=loud", LevelFilter::Info), Err(FilterError::BadLevel));
+
+    // targets are copied, the spec doesn't have to outlive the filter
+    let spec = alloc::format!("rust_stuff::gdt=off");
+    let filter = Filter::parse(&spec, LevelFilter::Info).expect("valid directives");
+    drop(spec);
+    assert_eq!(filter.level_for("rust_stuff::gdt"), LevelFilter::Off);
+    let long = alloc::format!("{:x<1$}=info", "", MAX_TARGET_LEN + 1);
+    assert_eq!(Filter::parse(&long, LevelFilter::Info), Err(FilterError::TargetTooLong));
+}
@@ -0,0 +1,206 @@
+//! Kernel logger
+//!
+//! Records go to the serial port as text and into a ring buffer of
+//! structured records, from which the KernelLog terminal is rendered.
+//! Before the heap is up and while interrupts are disabled they are kept
+//! in the lock-free `early` buffer instead, and moved over later.
+
+use crate::serial_println;
+use log::{LevelFilter, Metadata, Record, SetLoggerError};
+use crate::textbuffer::Textbuffer;
+use spin::Mutex;
+use alloc::{format, vec::Vec};
+use x86_64::instructions::interrupts;
+
+pub mod color;
+pub mod early;
+pub mod filter;
+pub mod ring;
+
+pub use filter::{Filter, FilterError};
+pub use ring::{LogRecord, LogRing};
+
+/// Filter of both the serial and the ring buffer output
+static FILTER: spin::RwLock<Filter> = spin::RwLock::new(Filter::new(log::LevelFilter::Info));
+
+static LOG: Mutex<LogRing> = Mutex::new(LogRing::new());
+
+struct KernelLogger;
+
+impl log::Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FILTER.read().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let time = crate::time::get();
+            serial_println!(
+                "[{:<5} from {:>25}:{:<3} at {:>5}] {}",
+                color::level(record.level(), record.level()),
+                color::source(record.file().unwrap_or("unknown source")),
+                color::source(record.line().unwrap_or_default()),
+                time,
+                record.args()
+            );
+            // interrupts are disabled in handlers, and by anything an
+            // interrupt handler could be waiting for
+            if crate::allocator::HEAP_INITIALIZED.get() == Some(&true) && interrupts::are_enabled() {
+                if let Some(mut log) = LOG.try_lock() {
+                    drain_early(&mut log);
+                    log.push(LogRecord::new(record, time));
+                    return;
+                }
+            }
+            early::push(record, time);
+        }
+    }
+
+    fn flush(&self) {
+        with_log(|log| log.clear());
+    }
+}
+
+static LOGGER: KernelLogger = KernelLogger;
+
+/// Installs the logger with the filter from the boot configuration
+pub fn init() -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    color::set_ansi(crate::config::get().log_color);
+    set_filter(crate::config::get().log_filter);
+    Ok(())
+}
+
+/// Replaces the log filter, e.g. from the console's `log` command
+pub fn set_filter(filter: Filter) {
+    // a log call from an interrupt handler would wait for the lock forever
+    interrupts::without_interrupts(|| *FILTER.write() = filter);
+    log::set_max_level(filter.max_level());
+}
+
+pub fn filter() -> Filter {
+    *FILTER.read()
+}
+
+fn drain_early(log: &mut LogRing) {
+    early::drain(|record, time| log.push(LogRecord::new(record, time)));
+}
+
+/// Runs `f` on the ring buffer, after moving the early records into it
+///
+/// Must not be called before the heap is initialized.
+fn with_log<R>(f: impl FnOnce(&mut LogRing) -> R) -> R {
+    // an interrupt handler logging meanwhile uses the early buffer
+    let mut log = LOG.lock();
+    drain_early(&mut log);
+    f(&mut log)
+}
+
+/// Moves the records logged before the heap was initialized into the ring buffer
+pub fn drain() {
+    with_log(|_| ());
+}
+
+/// Copies the buffered records at `level` or more severe, starting at sequence number `since`
+pub fn records(level: LevelFilter, since: u64) -> Vec<LogRecord> {
+    with_log(|log| log.records(level, since))
+}
+
+/// Number of records dropped from the full ring buffer, or lost because
+/// the early buffer was full
+pub fn dropped() -> u64 {
+    with_log(|log| log.dropped()) + early::dropped()
+}
+
+/// Writes the last `count` buffered records as text, e.g. to the panic log
+///
+/// Does not allocate, and writes nothing if the ring buffer is locked.
+pub fn write_recent(out: &mut impl core::fmt::Write, count: usize) -> core::fmt::Result {
+    let log = match LOG.try_lock() {
+        Some(log) => log,
+        None => return Ok(()),
+    };
+    let skip = log.iter().count().saturating_sub(count);
+    for record in log.iter().skip(skip) {
+        writeln!(
+            out,
+            "[{:<5} from {:>25}:{:<3} at {:>5}] {}",
+            record.level,
+            record.file.unwrap_or("unknown source"),
+            record.line.unwrap_or_default(),
+            record.time,
+            record.message,
+        )?;
+    }
+    Ok(())
+}
+
+/// Output of the last `with_rendered`, with what it was rendered from
+struct Rendered {
+    level: LevelFilter,
+    next_seq: u64,
+    dropped: u64,
+    early_dropped: u64,
+    buffer: Textbuffer,
+}
+
+static RENDERED: Mutex<Option<Rendered>> = Mutex::new(None);
+
+/// Calls `f` with the buffered records at `level` or more severe, one per line
+///
+/// The text is kept and only formatted again once records were added or
+/// dropped, or for another level.
+pub fn with_rendered<R>(level: LevelFilter, f: impl FnOnce(&Textbuffer) -> R) -> R {
+    let mut rendered = RENDERED.lock();
+    {
+        let mut log = LOG.lock();
+        drain_early(&mut log);
+        let current = matches!(
+            &*rendered,
+            Some(rendered) if rendered.level == level
+                && rendered.next_seq == log.next_seq()
+                && rendered.dropped == log.dropped()
+                && rendered.early_dropped == early::dropped()
+        );
+        if !current {
+            *rendered = Some(Rendered {
+                level,
+                next_seq: log.next_seq(),
+                dropped: log.dropped(),
+                early_dropped: early::dropped(),
+                buffer: render(&log, level),
+            });
+        }
+    }
+    f(&rendered.as_ref().expect("rendered above").buffer)
+}
+
+fn render(log: &LogRing, level: LevelFilter) -> Textbuffer {
+    use core::fmt::Write;
+
+    let mut buffer = Textbuffer::new();
+    if log.dropped() > 0 {
+        write!(buffer, "({} older records dropped)", log.dropped()).ok();
+        buffer.new_line();
+    }
+    if early::dropped() > 0 {
+        write!(buffer, "({} records lost while the log was busy)", early::dropped()).ok();
+        buffer.new_line();
+    }
+    for record in log.iter().filter(|record| record.level <= level) {
+        buffer.write_string("[");
+        buffer.write_string_color(&format!("{:<5}", record.level), color::vga_level(record.level));
+        buffer.write_string(" from ");
+        let source = format!("{:>25}:{:<3}", record.file.unwrap_or("unknown source"), record.line.unwrap_or_default());
+        buffer.write_string_color(&source, color::vga_source());
+        write!(buffer, " at {:>5}] ", record.time).ok();
+        for (index, line) in record.message.split('\n').enumerate() {
+            if index > 0 {
+                buffer.new_line();
+            }
+            buffer.write_string(line);
+        }
+        buffer.new_line();
+    }
+    buffer
+}
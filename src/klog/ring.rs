@@ -0,0 +1,112 @@
+//! Fixed capacity ring buffer of log records
+//!
+//! Records keep their level, source and time, so views can filter them and
+//! format them however they like. Once the buffer is full the oldest record
+//! is dropped for every new one; sequence numbers show the gaps.
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use log::{Level, LevelFilter, Record};
+
+/// Records kept before the oldest are dropped
+pub const CAPACITY: usize = 1024;
+
+/// Longer messages are cut off, so the buffer's size stays bounded
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    /// Increases by one for every record, also for dropped ones
+    pub seq: u64,
+    pub level: Level,
+    pub target: String,
+    pub file: Option<&'static str>,
+    pub line: Option<u32>,
+    /// Timer ticks since boot
+    pub time: usize,
+    pub message: String,
+}
+
+impl LogRecord {
+    /// Copies the record, its sequence number is set by `LogRing::push`
+    pub fn new(record: &Record, time: usize) -> LogRecord {
+        let mut message = String::new();
+        write!(TruncatingWriter { string: &mut message }, "{}", record.args()).ok();
+        LogRecord {
+            seq: 0,
+            level: record.level(),
+            target: record.target().into(),
+            file: record.file_static(),
+            line: record.line(),
+            time,
+            message,
+        }
+    }
+}
+
+/// Stops appending at `MAX_MESSAGE_LEN` bytes, on a character boundary
+struct TruncatingWriter<'a> {
+    string: &'a mut String,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            if self.string.len() + character.len_utf8() > MAX_MESSAGE_LEN {
+                return Err(fmt::Error);
+            }
+            self.string.push(character);
+        }
+        Ok(())
+    }
+}
+
+pub struct LogRing {
+    records: VecDeque<LogRecord>,
+    next_seq: u64,
+}
+
+impl LogRing {
+    pub const fn new() -> LogRing {
+        LogRing {
+            records: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Adds a record, dropping the oldest if the buffer is full
+    pub fn push(&mut self, mut record: LogRecord) {
+        if self.records.capacity() == 0 {
+            self.records.reserve_exact(CAPACITY);
+        }
+        if self.records.len() == CAPACITY {
+            self.records.pop_front();
+        }
+        record.seq = self.next_seq;
+        self.records.push_back(record);
+        self.next_seq += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Number of records dropped or cleared so far
+    pub fn dropped(&self) -> u64 {
+        self.next_seq - self.records.len() as u64
+    }
+
+    /// Sequence number the next record gets
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LogRecord> {
+        self.records.iter()
+    }
+
+    /// Copies the records at `level` or more severe with a sequence number of at least `since`
+    pub fn records(&self, level: LevelFilter, since: u64) -> Vec<LogRecord> {
+        self.records.iter().filter(|record| record.level <= level && record.seq >= since).cloned().collect()
+    }
+}
@@ -10,11 +10,23 @@
 extern crate alloc;
 extern crate rlibc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod ata;
+pub mod backtrace;
+pub mod block;
+pub mod config;
+pub mod console;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
+pub mod ipc;
 pub mod klog;
 pub mod memory;
+pub mod monitor;
+pub mod panic_log;
+pub mod pci;
+pub mod profiler;
 pub mod serial;
 pub mod vga;
 pub mod task;
@@ -22,18 +34,40 @@ pub mod time;
 pub mod textbuffer;
 pub mod gui;
 pub mod peripheral;
+pub mod sync;
+pub mod thread;
+pub mod process;
+pub mod smp;
 
 use core::panic::PanicInfo;
 
 pub fn init() {
     klog::init().expect("couldn't init logger");
+    config::report_invalid();
+    smp::init_bsp();
     gdt::init();
+    process::init();
     peripheral::mouse::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    serial::init();
     x86_64::instructions::interrupts::enable();
 }
 
+/// Resets the machine through the keyboard controller, keeping the contents of RAM
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+
+    x86_64::instructions::interrupts::disable();
+    let mut status: Port<u8> = Port::new(0x64);
+    unsafe {
+        // wait for the controller's input buffer to be empty
+        while status.read() & 0b10 != 0 {}
+        status.write(0xfe);
+    }
+    hlt_loop();
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
@@ -63,6 +97,8 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 
 pub trait Testable {
     fn run(&self) -> ();
+
+    fn name(&self) -> &'static str;
 }
 
 impl<T> Testable for T
@@ -70,15 +106,22 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        serial_print!("{}...\t", self.name());
         self();
         serial_println!("[ok]");
     }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
 }
 
+/// Runs the tests, only those matching the `test` option if it is set
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
+    let filter = config::get().test_filter;
+    let selected = || tests.iter().filter(|test| filter.map_or(true, |filter| test.name().contains(filter)));
+    serial_println!("Running {} of {} tests", selected().count(), tests.len());
+    for test in selected() {
         test.run();
     }
     exit_qemu(QemuExitCode::Success);
@@ -87,6 +130,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    backtrace::print();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -104,7 +148,7 @@ fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, 1024 * 1024 * 4, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap(&mut mapper, config::get().heap_size, &mut frame_allocator).expect("heap initialization failed");
 
     init();
     test_main();
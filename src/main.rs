@@ -16,7 +16,7 @@ entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     use rust_stuff::allocator;
     use rust_stuff::memory::{self, BootInfoFrameAllocator};
-    use rust_stuff::task::{Task, executor::Executor, keyboard, mouse, term, canvasgame};
+    use rust_stuff::task::{Task, Priority, executor::Executor, keyboard, mouse, serial, term, canvasgame};
     use rust_stuff::peripheral::{ISubject, keyboard::Keyboard, mouse::Mouse};
     use rust_stuff::vga::term::TERM_INPUT;
 
@@ -26,13 +26,29 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
     
-    allocator::init_heap(&mut mapper, 1024 * 1024 * 16, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap(&mut mapper, rust_stuff::config::get().heap_size, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(phys_mem_offset, frame_allocator);
+    rust_stuff::panic_log::init(&boot_info.memory_regions);
+    rust_stuff::klog::drain();
+    rust_stuff::panic_log::report();
     
     #[cfg(test)]
     test_main();
 
     log::info!("Welcome");
 
+    // the executor keeps running on the boot stack, as the first kernel thread
+    rust_stuff::thread::init("executor");
+
+    // the other CPUs run executors of their own, see `task::executor::spawn_anywhere`
+    if let Some(rsdp) = boot_info.rsdp_addr.into_option() {
+        rust_stuff::smp::start_aps(x86_64::PhysAddr::new(rsdp), ap_main);
+    }
+
+    // drivers register before the bus is scanned, and after the APs, MSI needs the local APIC
+    rust_stuff::ata::init();
+    rust_stuff::pci::init(boot_info.rsdp_addr.into_option().map(x86_64::PhysAddr::new));
+
     let mut keyboard = Keyboard::new();
     keyboard.attach(&*TERM_INPUT);
 
@@ -40,21 +56,37 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     mouse.attach(&*TERM_INPUT);
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(term::process_buffer()));
-    executor.spawn(Task::new(keyboard::process_keypresses(keyboard)));
-    executor.spawn(Task::new(mouse::process_states(mouse)));
-    executor.spawn(Task::new(canvasgame::run()));
+    executor.spawn(Task::new(term::process_buffer()).with_name("term"));
+    executor.spawn(Task::new(keyboard::process_keypresses(keyboard)).with_name("keyboard").with_priority(Priority::High));
+    executor.spawn(Task::new(mouse::process_states(mouse)).with_name("mouse").with_priority(Priority::High));
+    executor.spawn(Task::new(serial::process_input()).with_name("serial").with_priority(Priority::High));
+    if let Some(port) = rust_stuff::config::get().gdb_port {
+        executor.spawn(Task::new(rust_stuff::gdb::serve(port)).with_name("gdb").with_priority(Priority::High));
+    }
+    executor.spawn(Task::new(canvasgame::run()).with_name("canvasgame").with_priority(Priority::Low));
     executor.run();
 }
 
+fn ap_main() -> ! {
+    rust_stuff::task::executor::Executor::new().run()
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    use rust_stuff::config::PanicAction;
     use rust_stuff::QemuExitCode;
 
+    x86_64::instructions::interrupts::disable();
+    rust_stuff::panic_log::record(info);
     rust_stuff::serial_println!("{}", info);
     rust_stuff::println!("{}", info);
-    x86_64::instructions::interrupts::disable();
+    rust_stuff::backtrace::print();
+    match rust_stuff::config::get().panic {
+        PanicAction::Monitor => rust_stuff::monitor::enter_panic(info),
+        PanicAction::Reboot => rust_stuff::reboot(),
+        PanicAction::Halt => {}
+    }
     rust_stuff::exit_qemu(QemuExitCode::Failed);
     rust_stuff::hlt_loop();
 }
@@ -1,9 +1,23 @@
 use log::trace;
 use x86_64::structures::paging::{
-    FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+    mapper::MapToError, FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 use core::ops::Deref;
+use conquer_once::spin::OnceCell;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Offset at which the bootloader mapped the complete physical memory
+pub static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// Frame of the level 4 table the kernel booted with
+pub static KERNEL_LEVEL_4_TABLE: OnceCell<PhysFrame> = OnceCell::uninit();
+
+lazy_static! {
+    /// Frame allocator used for mappings made after boot, e.g. process address spaces
+    pub static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+}
 
 /// Initialize a new MappedPageTable.
 ///
@@ -99,6 +113,10 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: u64) -> Option<P
 
 use bootloader::boot_info::{MemoryRegions, MemoryRegionKind};
 
+/// Frames below this address are never handed out by the frame allocator,
+/// they are kept for code that must run in real mode, see `low_frame`
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_regions: &'static MemoryRegions,
@@ -126,12 +144,27 @@ impl BootInfoFrameAllocator {
         // map each region to its address range
         let addr_ranges = usable_regions.map(|r| r.start..r.end);
         // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096))
+            .filter(|addr| *addr >= LOW_MEMORY_END);
         // create `UnusedPhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
 
+impl BootInfoFrameAllocator {
+    /// Returns a usable frame below 1 MiB, e.g. for the trampoline that starts
+    /// the other CPUs in real mode. Always the same one, it is never allocated.
+    pub fn low_frame(&self) -> Option<PhysFrame> {
+        self.memory_regions.deref().iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| (r.start + 4095) & !4095..r.end.min(LOW_MEMORY_END))
+            .flat_map(|r| r.step_by(4096))
+            // the frame at 0 holds the real mode interrupt vectors
+            .find(|addr| *addr != 0 && addr + 4096 <= LOW_MEMORY_END && !crate::panic_log::REGION.contains(addr))
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
@@ -139,3 +172,104 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+/// Makes the frame allocator and physical memory mapping available
+/// to code running after boot.
+///
+/// Must be called after the heap was initialized with `frame_allocator`.
+pub fn install(physical_memory_offset: VirtAddr, frame_allocator: BootInfoFrameAllocator) {
+    use x86_64::registers::control::Cr3;
+
+    PHYSICAL_MEMORY_OFFSET.try_init_once(|| physical_memory_offset)
+        .expect("memory::install should only be called once");
+    KERNEL_LEVEL_4_TABLE.try_init_once(|| Cr3::read().0).ok();
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Returns the virtual address the given physical address is mapped at
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.try_get().expect("memory::install not called");
+    *offset + addr.as_u64()
+}
+
+/// Returns a mapper for the page table with the given level 4 frame
+///
+/// This function is unsafe because the caller must guarantee that the frame
+/// contains a valid level 4 table, and that no other mapper for it exists.
+pub unsafe fn mapper_for(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    let offset = *PHYSICAL_MEMORY_OFFSET.try_get().expect("memory::install not called");
+    let table: *mut PageTable = phys_to_virt(level_4_frame.start_address()).as_mut_ptr();
+    OffsetPageTable::new(&mut *table, offset)
+}
+
+/// Whether `addr` is mapped in the active page table, read without taking any locks
+///
+/// Used by the backtrace and the debugger to check addresses before
+/// dereferencing them. False before `install`.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::mapper::Translate;
+
+    if PHYSICAL_MEMORY_OFFSET.try_get().is_err() {
+        return false;
+    }
+    // the mapper is only used to read the tables
+    let mapper = unsafe { mapper_for(Cr3::read().0) };
+    mapper.translate_addr(addr).is_some()
+}
+
+/// Allocates a frame from the global frame allocator
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut().and_then(|allocator| allocator.allocate_frame())
+}
+
+/// Returns the frame from `BootInfoFrameAllocator::low_frame`
+pub fn low_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_ref().and_then(|allocator| allocator.low_frame())
+}
+
+/// Maps `size` bytes of memory mapped I/O at `addr` uncached into the kernel's
+/// address space, at the address `phys_to_virt` returns for it
+///
+/// Pages that are already mapped, e.g. because they are part of the
+/// physical memory mapping, are left as they are.
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Translate};
+
+    let level_4_frame = *KERNEL_LEVEL_4_TABLE.try_get().expect("memory::install not called");
+    let mut mapper = unsafe { mapper_for(level_4_frame) };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().expect("memory::install not called");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE;
+
+    let first = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last = PhysFrame::<Size4KiB>::containing_address(addr + size.max(1) - 1u64);
+    for frame in PhysFrame::range_inclusive(first, last) {
+        let page = Page::containing_address(phys_to_virt(frame.start_address()));
+        if mapper.translate_addr(page.start_address()).is_some() {
+            continue;
+        }
+        unsafe { mapper.map_to(page, frame, flags, allocator)?.flush() };
+    }
+    Ok(phys_to_virt(addr))
+}
+
+/// Maps `frame` at the virtual address equal to its physical address in the
+/// kernel's address space, unless it already is
+pub fn identity_map(frame: PhysFrame) -> Result<(), MapToError<Size4KiB>> {
+    use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Translate};
+
+    let level_4_frame = *KERNEL_LEVEL_4_TABLE.try_get().expect("memory::install not called");
+    let mut mapper = unsafe { mapper_for(level_4_frame) };
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match mapper.translate_addr(page.start_address()) {
+        Some(addr) if addr == frame.start_address() => return Ok(()),
+        Some(addr) => return Err(MapToError::PageAlreadyMapped(PhysFrame::containing_address(addr))),
+        None => {}
+    }
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().expect("memory::install not called");
+    unsafe { mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, allocator)?.flush() };
+    Ok(())
+}
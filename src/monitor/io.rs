@@ -0,0 +1,102 @@
+//! Polled input and unbuffered output of the monitor
+//!
+//! The monitor runs with interrupts disabled and the rest of the kernel
+//! stopped, so it reads the PS/2 controller and the console's serial port
+//! directly and writes to the screen without going through `Term`.
+
+use core::fmt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
+use crate::serial::{self, SerialStream};
+use crate::vga::writer::{WriterMode, WRITER};
+
+/// Longest command line
+pub const LINE_LEN: usize = 78;
+
+pub struct MonitorIo {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    data: Port<u8>,
+    status: Port<u8>,
+}
+
+impl MonitorIo {
+    /// Switches the screen to text mode and clears it
+    pub fn new() -> MonitorIo {
+        // the screen is skipped if the monitor stopped the kernel while it was drawing
+        if let Some(mut writer) = WRITER.try_lock() {
+            if writer.mode != WriterMode::Text {
+                writer.change_mode(WriterMode::Text);
+            }
+            writer.clear();
+        }
+        MonitorIo {
+            keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+            data: Port::new(0x60),
+            status: Port::new(0x64),
+        }
+    }
+
+    /// Waits for a key on the keyboard or a byte on the serial console
+    fn read_char(&mut self) -> char {
+        loop {
+            if let Some(byte) = serial::read_byte(SerialStream::Console) {
+                return match byte {
+                    b'\r' => '\n',
+                    0x7f => '\u{8}',
+                    byte => char::from(byte),
+                };
+            }
+            let status = unsafe { self.status.read() };
+            // bit 0: a byte is waiting, bit 5: it is from the mouse
+            if status & 0b1 != 0 {
+                let scancode = unsafe { self.data.read() };
+                if status & 0b10_0000 == 0 {
+                    if let Ok(Some(event)) = self.keyboard.add_byte(scancode) {
+                        if let Some(DecodedKey::Unicode(character)) = self.keyboard.process_keyevent(event) {
+                            return character;
+                        }
+                    }
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Reads a line with echo, returns its length in `line`
+    pub fn read_line(&mut self, line: &mut [u8; LINE_LEN]) -> usize {
+        let mut len = 0;
+        loop {
+            match self.read_char() {
+                '\n' => {
+                    self.write_str("\n").ok();
+                    return len;
+                }
+                '\u{8}' if len > 0 => {
+                    len -= 1;
+                    serial::write_bytes(SerialStream::Console, b"\x08 \x08");
+                    if let Some(mut writer) = WRITER.try_lock() {
+                        writer.backspace();
+                    }
+                }
+                character if character.is_ascii() && !character.is_ascii_control() && len < LINE_LEN => {
+                    line[len] = character as u8;
+                    len += 1;
+                    self.write_char(character).ok();
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl fmt::Write for MonitorIo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            crate::serial::console_write_byte(byte);
+        }
+        if let Some(mut writer) = WRITER.try_lock() {
+            writer.write_string(s);
+        }
+        Ok(())
+    }
+}
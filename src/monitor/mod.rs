@@ -0,0 +1,387 @@
+//! Kernel monitor, a debugger prompt on the screen and serial console
+//!
+//! Entered on `int3` when no GDB is attached, with F10 or Ctrl-\ on the
+//! serial console, with the console's `monitor` command, and on panic with
+//! the `panic=monitor` boot option. The CPU that entered it polls for input
+//! with interrupts disabled, so the rest of the kernel on that CPU is
+//! stopped until `continue` or `step`.
+//!
+//! Commands that need locks (`tasks`) or the heap may fail or hang if the
+//! kernel was stopped while holding them.
+
+mod io;
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+use crate::backtrace::{Frame, Frames};
+use crate::gdb::TrapFrame;
+use io::{MonitorIo, LINE_LEN};
+
+/// Byte the serial console sends for Ctrl-\
+pub const SERIAL_HOTKEY: u8 = 0x1c;
+
+/// Set by `request`, so the breakpoint it raises is reported as such
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set by `step`, the next debug exception enters the monitor
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+/// Set while a CPU is in the monitor, traps on others are ignored
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+enum Reason<'a> {
+    Breakpoint,
+    Step,
+    Request,
+    Panic(&'a PanicInfo<'a>),
+}
+
+/// What to do after a command
+enum Flow {
+    Prompt,
+    Resume,
+}
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&mut Monitor, args: &str) -> Flow,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "regs",
+        usage: "regs",
+        help: "show the registers of the stopped code",
+        run: registers,
+    },
+    Command {
+        name: "x",
+        usage: "x ADDR [LEN]",
+        help: "hexdump LEN bytes at ADDR, 64 by default",
+        run: hexdump,
+    },
+    Command {
+        name: "pt",
+        usage: "pt ADDR",
+        help: "walk the page tables for ADDR",
+        run: page_table,
+    },
+    Command {
+        name: "bt",
+        usage: "bt",
+        help: "backtrace of the stopped code",
+        run: backtrace,
+    },
+    Command {
+        name: "tasks",
+        usage: "tasks",
+        help: "list the async tasks",
+        run: tasks,
+    },
+    Command {
+        name: "continue",
+        usage: "continue",
+        help: "leave the monitor and resume the kernel",
+        run: resume,
+    },
+    Command {
+        name: "step",
+        usage: "step",
+        help: "run one instruction, then enter the monitor again",
+        run: step,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "warm reboot",
+        run: reboot,
+    },
+];
+
+struct Monitor<'a> {
+    io: MonitorIo,
+    /// Registers of the stopped code, `None` after a panic
+    frame: Option<&'a mut TrapFrame>,
+}
+
+/// Stops the kernel and enters the monitor, from a task or the console
+pub fn request() {
+    REQUESTED.store(true, Ordering::Release);
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Clears the flag set by `request`, returns true if it was set
+///
+/// Called by the breakpoint handler on every breakpoint, also when the
+/// debugger handles it, so a request it took over doesn't mark a later
+/// breakpoint as requested.
+pub(crate) fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::AcqRel)
+}
+
+/// Called by the breakpoint handler when no debugger is attached, with
+/// the result of `take_request`
+pub(crate) fn breakpoint(frame: &mut TrapFrame, requested: bool) {
+    let reason = if requested { Reason::Request } else { Reason::Breakpoint };
+    enter(Some(frame), reason);
+}
+
+/// Called by the debug exception handler, returns false if the monitor
+/// didn't ask for the step
+pub(crate) fn debug(frame: &mut TrapFrame) -> bool {
+    if !STEPPING.swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    frame.finish_step();
+    enter(Some(frame), Reason::Step);
+    true
+}
+
+/// Enters the monitor from the panic handler
+///
+/// Only `reboot` leaves it; returns right away if the panic happened in
+/// the monitor itself.
+pub fn enter_panic(info: &PanicInfo) {
+    x86_64::instructions::interrupts::disable();
+    enter(None, Reason::Panic(info));
+}
+
+fn enter(frame: Option<&mut TrapFrame>, reason: Reason) {
+    if ACTIVE.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let mut monitor = Monitor { io: MonitorIo::new(), frame };
+    monitor.banner(reason);
+    let mut line = [0; LINE_LEN];
+    loop {
+        write!(monitor.io, "mon> ").ok();
+        let len = monitor.io.read_line(&mut line);
+        let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+        if let Flow::Resume = monitor.execute(line) {
+            break;
+        }
+    }
+    ACTIVE.store(false, Ordering::Release);
+    // redraws the active virtual terminal once the kernel runs again
+    crate::task::term::add_char('\0');
+}
+
+impl Monitor<'_> {
+    fn banner(&mut self, reason: Reason) {
+        write!(self.io, "kernel monitor on CPU {}, ", crate::smp::current_id()).ok();
+        match reason {
+            Reason::Breakpoint => write!(self.io, "breakpoint"),
+            Reason::Step => write!(self.io, "step"),
+            Reason::Request => write!(self.io, "requested"),
+            Reason::Panic(info) => write!(self.io, "panic: {}", info),
+        }
+        .ok();
+        if let Some(frame) = &self.frame {
+            write!(self.io, " at {}", Frame::new(VirtAddr::new(frame.rip))).ok();
+        }
+        writeln!(self.io, "\ntype `help` for the commands").ok();
+    }
+
+    fn execute(&mut self, line: &str) -> Flow {
+        let line = line.trim();
+        if line.is_empty() {
+            return Flow::Prompt;
+        }
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        // commands can be abbreviated, the first match wins
+        match COMMANDS.iter().find(|command| command.name.starts_with(name)) {
+            Some(command) => (command.run)(self, args.trim()),
+            None => {
+                writeln!(self.io, "unknown command `{}`, try `help`", name).ok();
+                Flow::Prompt
+            }
+        }
+    }
+}
+
+/// Parses a number, hex with a `0x` prefix
+fn parse_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn help(monitor: &mut Monitor, _args: &str) -> Flow {
+    for command in COMMANDS {
+        writeln!(monitor.io, "{:<16} {}", command.usage, command.help).ok();
+    }
+    Flow::Prompt
+}
+
+fn registers(monitor: &mut Monitor, _args: &str) -> Flow {
+    let frame = match &monitor.frame {
+        Some(frame) => frame,
+        None => {
+            writeln!(monitor.io, "no registers after a panic").ok();
+            return Flow::Prompt;
+        }
+    };
+    let registers = [
+        ("rax", frame.rax), ("rbx", frame.rbx), ("rcx", frame.rcx), ("rdx", frame.rdx),
+        ("rsi", frame.rsi), ("rdi", frame.rdi), ("rbp", frame.rbp), ("rsp", frame.rsp),
+        ("r8", frame.r8), ("r9", frame.r9), ("r10", frame.r10), ("r11", frame.r11),
+        ("r12", frame.r12), ("r13", frame.r13), ("r14", frame.r14), ("r15", frame.r15),
+        ("rip", frame.rip), ("rflags", frame.rflags), ("cs", frame.cs), ("ss", frame.ss),
+    ];
+    for (index, (name, value)) in registers.iter().enumerate() {
+        write!(monitor.io, "{:>6} {:016x}", name, value).ok();
+        monitor.io.write_str(if index % 3 == 2 { "\n" } else { "  " }).ok();
+    }
+    writeln!(monitor.io).ok();
+    Flow::Prompt
+}
+
+fn hexdump(monitor: &mut Monitor, args: &str) -> Flow {
+    let mut args = args.split_whitespace();
+    let address = match args.next().and_then(parse_number) {
+        Some(address) => address,
+        None => {
+            writeln!(monitor.io, "usage: x ADDR [LEN]").ok();
+            return Flow::Prompt;
+        }
+    };
+    let len = args.next().and_then(parse_number).unwrap_or(64).min(4096);
+    let range_end = match address.checked_add(len) {
+        Some(range_end) => range_end,
+        None => {
+            writeln!(monitor.io, "{:#x} + {:#x} wraps around", address, len).ok();
+            return Flow::Prompt;
+        }
+    };
+    for line in (address..range_end).step_by(16) {
+        let end = line.saturating_add(16).min(range_end);
+        if VirtAddr::try_new(line).map_or(true, |addr| !crate::memory::is_mapped(addr)) {
+            writeln!(monitor.io, "{:016x}: not mapped", line).ok();
+            return Flow::Prompt;
+        }
+        // a line never crosses a page if `address` is aligned, otherwise check its end too
+        if VirtAddr::try_new(end - 1).map_or(true, |addr| !crate::memory::is_mapped(addr)) {
+            writeln!(monitor.io, "{:016x}: not mapped", end - 1).ok();
+            return Flow::Prompt;
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(line as *const u8, (end - line) as usize) };
+        write!(monitor.io, "{:016x}:", line).ok();
+        for byte in bytes {
+            write!(monitor.io, " {:02x}", byte).ok();
+        }
+        for _ in bytes.len()..16 {
+            monitor.io.write_str("   ").ok();
+        }
+        monitor.io.write_str("  ").ok();
+        for byte in bytes {
+            let character = if byte.is_ascii_graphic() || *byte == b' ' { char::from(*byte) } else { '.' };
+            monitor.io.write_char(character).ok();
+        }
+        writeln!(monitor.io).ok();
+    }
+    Flow::Prompt
+}
+
+fn page_table(monitor: &mut Monitor, args: &str) -> Flow {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+    let addr = match args.split_whitespace().next().and_then(parse_number).and_then(|addr| VirtAddr::try_new(addr).ok()) {
+        Some(addr) => addr,
+        None => {
+            writeln!(monitor.io, "usage: pt ADDR").ok();
+            return Flow::Prompt;
+        }
+    };
+    let (level_4_frame, _) = Cr3::read();
+    writeln!(monitor.io, "CR3 {:#x}", level_4_frame.start_address().as_u64()).ok();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table_addr = level_4_frame.start_address();
+    for (level, index) in (1..=4).rev().zip(indexes.iter()) {
+        let table: &PageTable = unsafe { &*crate::memory::phys_to_virt(table_addr).as_ptr() };
+        let entry = &table[*index];
+        writeln!(monitor.io, "P{}[{:>3}] {:#014x} {:?}", level, u16::from(*index), entry.addr().as_u64(), entry.flags()).ok();
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            writeln!(monitor.io, "not mapped").ok();
+            return Flow::Prompt;
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 1u64 << (12 + 9 * (level - 1));
+            let phys = entry.addr().as_u64() + (addr.as_u64() & (page_size - 1));
+            writeln!(monitor.io, "-> {:#x}", phys).ok();
+            return Flow::Prompt;
+        }
+        table_addr = entry.addr();
+    }
+    Flow::Prompt
+}
+
+fn backtrace(monitor: &mut Monitor, _args: &str) -> Flow {
+    match &monitor.frame {
+        Some(frame) => {
+            let (rip, rbp) = (frame.rip, frame.rbp);
+            writeln!(monitor.io, "  {}", Frame::new(VirtAddr::new(rip))).ok();
+            for frame in Frames::from_rbp(rbp) {
+                writeln!(monitor.io, "  {}", frame).ok();
+            }
+        }
+        None => {
+            for frame in Frames::capture() {
+                writeln!(monitor.io, "  {}", frame).ok();
+            }
+        }
+    }
+    Flow::Prompt
+}
+
+fn tasks(monitor: &mut Monitor, _args: &str) -> Flow {
+    let tasks = match crate::task::info::try_list() {
+        Some(tasks) => tasks,
+        None => {
+            writeln!(monitor.io, "the task registry is locked").ok();
+            return Flow::Prompt;
+        }
+    };
+    writeln!(monitor.io, "{:>4} {:<16} {:<8} {:>8}", "ID", "NAME", "STATE", "POLLS").ok();
+    for task in tasks {
+        writeln!(monitor.io, "{:>4} {:<16.16} {:<8?} {:>8}", task.id, task.name, task.state, task.poll_count).ok();
+    }
+    Flow::Prompt
+}
+
+fn resume(monitor: &mut Monitor, _args: &str) -> Flow {
+    if monitor.frame.is_none() {
+        writeln!(monitor.io, "can't continue after a panic").ok();
+        return Flow::Prompt;
+    }
+    Flow::Resume
+}
+
+fn step(monitor: &mut Monitor, _args: &str) -> Flow {
+    let frame = match &mut monitor.frame {
+        Some(frame) => frame,
+        None => {
+            writeln!(monitor.io, "can't step after a panic").ok();
+            return Flow::Prompt;
+        }
+    };
+    frame.begin_step();
+    STEPPING.store(true, Ordering::Release);
+    Flow::Resume
+}
+
+fn reboot(_monitor: &mut Monitor, _args: &str) -> Flow {
+    crate::reboot()
+}
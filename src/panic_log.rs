@@ -0,0 +1,137 @@
+//! Panic log kept in RAM across warm reboots
+//!
+//! On panic, the last log records and the panic message are written to a
+//! fixed physical region below 1 MiB, which the frame allocator never hands
+//! out. A warm reset (`system_reset` in the QEMU monitor, or the
+//! `panic=reboot` boot option) keeps the contents of RAM, so the next boot
+//! finds the log there, repeats it in the kernel log and clears it.
+//! Firmware or the bootloader may still overwrite the region, the checksum
+//! detects that.
+
+use alloc::string::String;
+use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
+use core::fmt::{self, Write};
+use core::ops::Range;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Physical memory of the panic log
+pub const REGION: Range<u64> = 0x8_0000..0x8_8000;
+
+/// Log records written before the panic message
+pub const RECORDS: usize = 32;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"PANICLOG");
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    len: u32,
+    checksum: u32,
+}
+
+const TEXT_CAPACITY: usize = (REGION.end - REGION.start) as usize - core::mem::size_of::<Header>();
+
+/// Set by `init` if the memory map has the region as usable RAM
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Set while a panic is written, a panic while writing it is not recorded
+static WRITING: AtomicBool = AtomicBool::new(false);
+
+/// Checks that the region is RAM nothing else uses
+///
+/// Must be called after `memory::install`, before the first panic that
+/// should be recorded.
+pub fn init(memory_regions: &MemoryRegions) {
+    let usable = memory_regions
+        .iter()
+        .any(|region| region.kind == MemoryRegionKind::Usable && region.start <= REGION.start && REGION.end <= region.end);
+    if usable {
+        AVAILABLE.store(true, Ordering::Release);
+    } else {
+        log::warn!("panic log region {:#x}..{:#x} is not usable RAM, panics are not kept", REGION.start, REGION.end);
+    }
+}
+
+/// The header and the text area, if the region is available and mapped
+fn region() -> Option<(&'static mut Header, &'static mut [u8])> {
+    if !AVAILABLE.load(Ordering::Acquire) {
+        return None;
+    }
+    let offset = crate::memory::PHYSICAL_MEMORY_OFFSET.try_get().ok()?;
+    let start = *offset + REGION.start;
+    unsafe {
+        let header = &mut *start.as_mut_ptr::<Header>();
+        let text = core::slice::from_raw_parts_mut(
+            (start + core::mem::size_of::<Header>()).as_mut_ptr::<u8>(),
+            TEXT_CAPACITY,
+        );
+        Some((header, text))
+    }
+}
+
+/// FNV-1a
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193))
+}
+
+/// Appends to the text area, cutting off what doesn't fit
+struct RegionWriter<'a> {
+    text: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for RegionWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.text.len() - self.len);
+        self.text[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        if len < s.len() { Err(fmt::Error) } else { Ok(()) }
+    }
+}
+
+/// Writes the recent log records and the panic message to the region
+///
+/// Does not allocate or wait for locks, so it works whatever state the
+/// kernel panicked in.
+pub fn record(info: &PanicInfo) {
+    if WRITING.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Some((header, text)) = region() {
+        header.magic = 0;
+        let mut writer = RegionWriter { text, len: 0 };
+        crate::klog::write_recent(&mut writer, RECORDS).ok();
+        write!(writer, "{}", info).ok();
+        let len = writer.len;
+        header.len = len as u32;
+        header.checksum = checksum(&writer.text[..len]);
+        header.magic = MAGIC;
+    }
+    WRITING.store(false, Ordering::Release);
+}
+
+/// Returns the panic log of the previous boot, if there is one, and clears it
+pub fn take() -> Option<String> {
+    let (header, text) = region()?;
+    if header.magic != MAGIC {
+        return None;
+    }
+    header.magic = 0;
+    let text = text.get(..header.len as usize)?;
+    if checksum(text) != header.checksum {
+        log::warn!("panic log of the previous boot is corrupted");
+        return None;
+    }
+    Some(String::from_utf8_lossy(text).into_owned())
+}
+
+/// Repeats the panic log of the previous boot in the kernel log
+pub fn report() {
+    if let Some(text) = take() {
+        log::error!("the previous boot panicked, its last log records were:");
+        for line in text.lines() {
+            log::error!("| {}", line);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Configuration space access
+//!
+//! PCI Express machines map each function's 4 KiB of configuration space
+//! into memory (ECAM), at the regions listed in the ACPI MCFG. Older ones,
+//! and QEMU's default `pc` machine, only have the 256 bytes reachable
+//! through the legacy address and data ports. Only segment 0 is supported.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::acpi::EcamRegion;
+
+/// Bus, device and function of a PCI function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> PciAddress {
+        assert!(device < 32 && function < 8, "invalid PCI address");
+        PciAddress { bus, device, function }
+    }
+
+    /// Value of the legacy address port for the register at `offset`
+    fn legacy_address(self, offset: u16) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xfc)
+    }
+
+    /// Offset of the function's configuration space from the ECAM region of bus 0
+    fn ecam_offset(self) -> u64 {
+        u64::from(self.bus) << 20 | u64::from(self.device) << 15 | u64::from(self.function) << 12
+    }
+}
+
+impl fmt::Display for PciAddress {
+    /// `bus:device.function`, like `lspci`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+enum Mechanism {
+    Legacy {
+        address: Port<u32>,
+        data: Port<u32>,
+    },
+    /// The whole region is mapped, so accesses never allocate
+    Ecam {
+        region: EcamRegion,
+    },
+}
+
+static MECHANISM: Mutex<Mechanism> = Mutex::new(Mechanism::Legacy {
+    address: Port::new(0xcf8),
+    data: Port::new(0xcfc),
+});
+
+/// Switches to memory mapped access for the buses in `region`
+///
+/// Maps the configuration space of all its buses, up to 256 MiB, and keeps
+/// using the legacy ports if that fails.
+pub fn use_ecam(region: EcamRegion) {
+    let buses = u64::from(region.end_bus.saturating_sub(region.start_bus)) + 1;
+    if let Err(err) = crate::memory::map_mmio(region.base, buses << 20) {
+        log::warn!("failed to map PCI configuration space: {:?}", err);
+        return;
+    }
+    *MECHANISM.lock() = Mechanism::Ecam { region };
+}
+
+pub fn is_ecam() -> bool {
+    matches!(*MECHANISM.lock(), Mechanism::Ecam { .. })
+}
+
+/// Pointer to the register at `offset`, mapped by `use_ecam`
+fn ecam_register(region: &EcamRegion, address: PciAddress, offset: u16) -> Option<*mut u32> {
+    if address.bus < region.start_bus || address.bus > region.end_bus {
+        return None;
+    }
+    let bus_base = region.base + (u64::from(address.bus - region.start_bus) << 20);
+    let function_base = PhysAddr::new(bus_base.as_u64() + (address.ecam_offset() & 0xf_ffff));
+    Some(crate::memory::phys_to_virt(function_base + u64::from(offset & 0xffc)).as_mut_ptr())
+}
+
+/// Reads the aligned 32 bit register at `offset`, all ones if there is no such function
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    x86_64::instructions::interrupts::without_interrupts(|| match &mut *MECHANISM.lock() {
+        Mechanism::Legacy { address: address_port, data } if offset < 256 => unsafe {
+            address_port.write(address.legacy_address(offset));
+            data.read()
+        },
+        Mechanism::Ecam { region } => match ecam_register(region, address, offset) {
+            Some(register) => unsafe { core::ptr::read_volatile(register) },
+            None => u32::MAX,
+        },
+        _ => u32::MAX,
+    })
+}
+
+/// Writes the aligned 32 bit register at `offset`
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| match &mut *MECHANISM.lock() {
+        Mechanism::Legacy { address: address_port, data } if offset < 256 => unsafe {
+            address_port.write(address.legacy_address(offset));
+            data.write(value);
+        },
+        Mechanism::Ecam { region } => {
+            if let Some(register) = ecam_register(region, address, offset) {
+                unsafe { core::ptr::write_volatile(register, value) };
+            }
+        }
+        _ => {}
+    })
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    (read_u32(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    (read_u32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// Writes a 16 bit register, keeping the other half of its 32 bit word
+///
+/// Don't use it next to write-1-to-clear bits, like those in the status register.
+pub fn write_u16(address: PciAddress, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let word = read_u32(address, offset) & !(0xffff << shift);
+    write_u32(address, offset, word | u32::from(value) << shift);
+}
+
+#[test_case]
+fn test_addresses() {
+    let address = PciAddress::new(1, 0x1f, 3);
+    assert_eq!(address.legacy_address(0x3e), 0x8001_fb3c);
+    assert_eq!(address.ecam_offset(), 0x1f_b000);
+    assert_eq!(alloc::format!("{}", address), "01:1f.3");
+}
@@ -0,0 +1,77 @@
+//! Driver registry
+//!
+//! Drivers register a static `Driver` with the IDs or classes they handle;
+//! `bind_all` offers every unbound function to the first matching driver's
+//! `probe`, in registration order.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::Device;
+
+/// Functions a driver handles
+#[derive(Debug, Clone, Copy)]
+pub enum Match {
+    Id { vendor: u16, device: u16 },
+    /// `None` matches any subclass or programming interface
+    Class { class: u8, subclass: Option<u8>, prog_if: Option<u8> },
+}
+
+impl Match {
+    pub fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Match::Id { vendor, device: id } => device.vendor_id == vendor && device.device_id == id,
+            Match::Class { class, subclass, prog_if } => {
+                device.class == class
+                    && subclass.map_or(true, |subclass| device.subclass == subclass)
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverError {
+    /// The function isn't one the driver can handle after all
+    Unsupported,
+    /// The device didn't respond as expected
+    Device,
+    /// Mapping registers or allocating failed
+    Resources,
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Takes over the function, called once per matching function
+    pub probe: fn(&Device) -> Result<(), DriverError>,
+}
+
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+
+/// Adds a driver, functions found from now on are offered to it by `bind_all`
+pub fn register(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+}
+
+/// Offers every function without a driver to the matching drivers
+pub fn bind_all() {
+    let drivers = DRIVERS.lock().clone();
+    let unbound: Vec<Device> = super::devices().into_iter().filter(|device| device.driver.is_none()).collect();
+    for device in unbound {
+        for driver in drivers.iter().filter(|driver| driver.matches.iter().any(|m| m.matches(&device))) {
+            match (driver.probe)(&device) {
+                Ok(()) => {
+                    log::info!("{}: bound to {}", device.address, driver.name);
+                    super::with_devices(|devices| {
+                        if let Some(entry) = devices.iter_mut().find(|entry| entry.address == device.address) {
+                            entry.driver = Some(driver.name);
+                        }
+                    });
+                    break;
+                }
+                Err(DriverError::Unsupported) => {}
+                Err(err) => log::warn!("{}: {} failed to probe: {:?}", device.address, driver.name, err),
+            }
+        }
+    }
+}
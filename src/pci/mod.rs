@@ -0,0 +1,369 @@
+//! PCI bus enumeration and driver binding
+//!
+//! `init` picks the configuration space access (ECAM if the ACPI MCFG
+//! lists it, legacy ports otherwise), walks the buses behind the host
+//! bridges and PCI-to-PCI bridges, and offers every function found to the
+//! drivers registered with `driver::register`.
+
+pub mod config;
+pub mod driver;
+pub mod msi;
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::PhysAddr;
+pub use config::PciAddress;
+pub use driver::{Driver, DriverError, Match};
+
+// offsets in the configuration space header
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const MULTIFUNCTION: u8 = 0x80;
+const HEADER_BRIDGE: u8 = 0x01;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// A base address register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Unused,
+    Io { port: u16, size: u32 },
+    Memory { addr: PhysAddr, size: u64, prefetchable: bool, is_64bit: bool },
+}
+
+impl Bar {
+    /// Decodes a BAR from its value, the value read back after writing all
+    /// ones to it, and for 64 bit BARs those of the upper half
+    fn decode(value: u32, sized: u32, upper: Option<(u32, u32)>) -> Bar {
+        if value & 1 == 1 {
+            let mask = sized & !0b11;
+            if mask == 0 {
+                return Bar::Unused;
+            }
+            return Bar::Io {
+                port: (value & !0b11) as u16,
+                size: (!mask).wrapping_add(1) & 0xffff,
+            };
+        }
+        let (high, high_sized) = upper.unwrap_or((0, u32::MAX));
+        let mask = u64::from(high_sized) << 32 | u64::from(sized & !0xf);
+        if sized & !0xf == 0 && upper.map_or(true, |(_, sized)| sized == 0) {
+            return Bar::Unused;
+        }
+        Bar::Memory {
+            addr: PhysAddr::new(u64::from(high) << 32 | u64::from(value & !0xf)),
+            size: (!mask).wrapping_add(1),
+            prefetchable: value & 0b1000 != 0,
+            is_64bit: upper.is_some(),
+        }
+    }
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Unused => f.write_str("unused"),
+            Bar::Io { port, size } => write!(f, "I/O ports at {:#x} [size={:#x}]", port, size),
+            Bar::Memory { addr, size, prefetchable, is_64bit } => write!(
+                f,
+                "memory at {:#x} ({}-bit, {}) [size={:#x}]",
+                addr.as_u64(),
+                if is_64bit { 64 } else { 32 },
+                if prefetchable { "prefetchable" } else { "non-prefetchable" },
+                size,
+            ),
+        }
+    }
+}
+
+/// Capability in the function's capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset in configuration space
+    pub offset: u16,
+}
+
+impl Capability {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR: u8 = 0x09;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSI_X: u8 = 0x11;
+
+    pub fn name(&self) -> &'static str {
+        match self.id {
+            Self::POWER_MANAGEMENT => "power management",
+            Self::MSI => "MSI",
+            Self::VENDOR => "vendor specific",
+            Self::PCI_EXPRESS => "PCI Express",
+            Self::MSI_X => "MSI-X",
+            _ => "unknown",
+        }
+    }
+}
+
+/// A function found by `enumerate`
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Only the first two exist for bridges
+    pub bars: [Bar; 6],
+    /// IRQ the firmware routed the legacy interrupt to, 0xff if none
+    pub interrupt_line: u8,
+    /// 1-4 for INTA-INTD, 0 if the function doesn't use one
+    pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability>,
+    /// Name of the driver that took the function
+    pub driver: Option<&'static str>,
+}
+
+impl Device {
+    fn read(address: PciAddress) -> Option<Device> {
+        let vendor_id = config::read_u16(address, VENDOR_ID);
+        if vendor_id == 0xffff {
+            return None;
+        }
+        let class = config::read_u32(address, REVISION);
+        let header_type = config::read_u8(address, HEADER_TYPE) & !MULTIFUNCTION;
+        let interrupt = config::read_u16(address, INTERRUPT_LINE);
+        let mut device = Device {
+            address,
+            vendor_id,
+            device_id: config::read_u16(address, DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            bars: [Bar::Unused; 6],
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            capabilities: Vec::new(),
+            driver: None,
+        };
+        device.read_bars();
+        device.read_capabilities();
+        Some(device)
+    }
+
+    /// Sizes the BARs by writing all ones, with decoding turned off meanwhile
+    fn read_bars(&mut self) {
+        let count = match self.header_type {
+            0x00 => 6,
+            HEADER_BRIDGE => 2,
+            _ => 0,
+        };
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO | COMMAND_MEMORY));
+        let mut index = 0;
+        while index < count {
+            let offset = BAR0 + index as u16 * 4;
+            let (value, sized) = self.size_register(offset);
+            let is_64bit = value & 0b111 == 0b100;
+            let upper = if is_64bit && index + 1 < count { Some(self.size_register(offset + 4)) } else { None };
+            self.bars[index] = Bar::decode(value, sized, upper);
+            index += if upper.is_some() { 2 } else { 1 };
+        }
+        self.set_command(command);
+    }
+
+    /// Original value of the register, and what it reads after writing all ones
+    fn size_register(&self, offset: u16) -> (u32, u32) {
+        let value = config::read_u32(self.address, offset);
+        config::write_u32(self.address, offset, u32::MAX);
+        let sized = config::read_u32(self.address, offset);
+        config::write_u32(self.address, offset, value);
+        (value, sized)
+    }
+
+    fn read_capabilities(&mut self) {
+        if config::read_u16(self.address, STATUS) & STATUS_CAPABILITIES == 0 {
+            return;
+        }
+        let mut offset = u16::from(config::read_u8(self.address, CAPABILITIES_POINTER) & !0b11);
+        // a broken list could loop, there is room for at most 48 capabilities
+        for _ in 0..48 {
+            if offset < 0x40 {
+                break;
+            }
+            let header = config::read_u16(self.address, offset);
+            self.capabilities.push(Capability { id: header as u8, offset });
+            offset = (header >> 8) & !0b11;
+        }
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().copied().find(|capability| capability.id == id)
+    }
+
+    pub fn command(&self) -> u16 {
+        config::read_u16(self.address, COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        // the status half is write-1-to-clear, writing zeros leaves it alone
+        config::write_u32(self.address, COMMAND, u32::from(command));
+    }
+
+    /// Turns on I/O and memory decoding and bus mastering, for drivers
+    pub fn enable(&self) {
+        self.set_command(self.command() | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+impl fmt::Display for Device {
+    /// One line like `lspci -nn`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: [{:04x}:{:04x}] (rev {:02x})",
+            self.address, self.class_name(), self.class, self.subclass, self.vendor_id, self.device_id, self.revision,
+        )?;
+        if let Some(driver) = self.driver {
+            write!(f, " driver {}", driver)?;
+        }
+        Ok(())
+    }
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        _ => "Unclassified device",
+    }
+}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+/// Scans a bus and the buses behind its bridges
+fn scan_bus(bus: u8, devices: &mut Vec<Device>) {
+    for device in 0..32 {
+        let function_0 = match Device::read(PciAddress::new(bus, device, 0)) {
+            Some(function) => function,
+            None => continue,
+        };
+        let functions = if config::read_u8(function_0.address, HEADER_TYPE) & MULTIFUNCTION != 0 { 8 } else { 1 };
+        scan_function(function_0, devices);
+        for function in 1..functions {
+            if let Some(function) = Device::read(PciAddress::new(bus, device, function)) {
+                scan_function(function, devices);
+            }
+        }
+    }
+}
+
+fn scan_function(function: Device, devices: &mut Vec<Device>) {
+    let secondary_bus = if function.header_type == HEADER_BRIDGE && (function.class, function.subclass) == (0x06, 0x04) {
+        Some(config::read_u8(function.address, SECONDARY_BUS))
+    } else {
+        None
+    };
+    devices.push(function);
+    // bus 0 is never behind a bridge, a 0 means the firmware didn't set it up
+    if let Some(bus) = secondary_bus.filter(|bus| *bus != 0) {
+        scan_bus(bus, devices);
+    }
+}
+
+/// Finds all functions, replacing the previous list
+pub fn enumerate() -> usize {
+    let mut devices = Vec::new();
+    // a multifunction host bridge means one host controller, and bus, per function
+    let host = PciAddress::new(0, 0, 0);
+    if config::read_u8(host, HEADER_TYPE) & MULTIFUNCTION == 0 {
+        scan_bus(0, &mut devices);
+    } else {
+        for function in 0..8 {
+            if config::read_u16(PciAddress::new(0, 0, function), VENDOR_ID) != 0xffff {
+                scan_bus(function, &mut devices);
+            }
+        }
+    }
+    let count = devices.len();
+    *DEVICES.lock() = devices;
+    count
+}
+
+/// Chooses the configuration access, enumerates the buses and binds drivers
+///
+/// Must be called after `memory::install`.
+pub fn init(rsdp: Option<PhysAddr>) {
+    let region = rsdp.and_then(|rsdp| crate::acpi::mcfg(rsdp).ok()).and_then(|regions| {
+        regions.into_iter().find(|region| region.segment == 0)
+    });
+    if let Some(region) = region {
+        log::debug!("PCI configuration space at {:#x} for buses {}-{}", region.base.as_u64(), region.start_bus, region.end_bus);
+        config::use_ecam(region);
+    }
+    let count = enumerate();
+    log::info!("found {} PCI functions using {} configuration access", count, if config::is_ecam() { "ECAM" } else { "legacy" });
+    driver::bind_all();
+}
+
+/// Copies the list of functions found by `enumerate`
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+/// Runs `f` on every function and its list entry, e.g. to bind drivers
+fn with_devices<R>(f: impl FnOnce(&mut Vec<Device>) -> R) -> R {
+    f(&mut DEVICES.lock())
+}
+
+#[test_case]
+fn test_decode_bars() {
+    assert_eq!(Bar::decode(0xc001, 0xfff1, None), Bar::Io { port: 0xc000, size: 0x10 });
+    assert_eq!(
+        Bar::decode(0xfebf_0000, 0xffff_f000, None),
+        Bar::Memory { addr: PhysAddr::new(0xfebf_0000), size: 0x1000, prefetchable: false, is_64bit: false },
+    );
+    assert_eq!(
+        Bar::decode(0x0000_000c, 0xffff_c00c, Some((0x1, 0xffff_ffff))),
+        Bar::Memory { addr: PhysAddr::new(0x1_0000_0000), size: 0x4000, prefetchable: true, is_64bit: true },
+    );
+    assert_eq!(Bar::decode(0, 0, None), Bar::Unused);
+}
@@ -0,0 +1,85 @@
+//! Message signalled interrupts
+//!
+//! MSI and MSI-X make the function write a message to the local APIC
+//! instead of asserting a legacy interrupt line. Messages go to the
+//! bootstrap processor, at a vector from `interrupts::allocate_msi_vector`.
+
+use x86_64::PhysAddr;
+use super::{config, Bar, Capability, Device, COMMAND_INTX_DISABLE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function has no MSI or MSI-X capability
+    NotSupported,
+    /// The local APIC isn't set up, so nothing would handle the message
+    NoApic,
+    /// The MSI-X table index is past the end of the table
+    NoSuchEntry,
+    /// The MSI-X table's BAR is missing or couldn't be mapped
+    Table,
+}
+
+/// Address and data of a fixed, edge triggered interrupt at `vector` for the BSP
+fn message(vector: u8) -> Result<(u64, u32), MsiError> {
+    if !crate::smp::apic::is_initialized() {
+        return Err(MsiError::NoApic);
+    }
+    let apic_id = crate::smp::cpu(0).map_or(0, |cpu| cpu.apic_id);
+    Ok((0xfee0_0000 | u64::from(apic_id) << 12, u32::from(vector)))
+}
+
+/// Enables MSI with a single message, turning off the legacy interrupt
+pub fn enable_msi(device: &Device, vector: u8) -> Result<(), MsiError> {
+    const ENABLE: u16 = 1 << 0;
+    const MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+    const ADDRESS_64BIT: u16 = 1 << 7;
+
+    let capability = device.capability(Capability::MSI).ok_or(MsiError::NotSupported)?;
+    let (address, data) = message(vector)?;
+    let (offset, function) = (capability.offset, device.address);
+    let control = config::read_u16(function, offset + 2);
+    config::write_u32(function, offset + 4, address as u32);
+    let data_offset = if control & ADDRESS_64BIT != 0 {
+        config::write_u32(function, offset + 8, (address >> 32) as u32);
+        offset + 12
+    } else {
+        offset + 8
+    };
+    config::write_u16(function, data_offset, data as u16);
+    config::write_u16(function, offset + 2, (control & !MULTIPLE_MESSAGE_ENABLE) | ENABLE);
+    device.set_command(device.command() | COMMAND_INTX_DISABLE);
+    Ok(())
+}
+
+/// Points MSI-X table entry `entry` at `vector`, unmasks it and enables MSI-X
+pub fn enable_msix(device: &Device, entry: u16, vector: u8) -> Result<(), MsiError> {
+    const TABLE_SIZE: u16 = 0x7ff;
+    const FUNCTION_MASK: u16 = 1 << 14;
+    const ENABLE: u16 = 1 << 15;
+    const ENTRY_SIZE: u64 = 16;
+
+    let capability = device.capability(Capability::MSI_X).ok_or(MsiError::NotSupported)?;
+    let (address, data) = message(vector)?;
+    let function = device.address;
+    let control = config::read_u16(function, capability.offset + 2);
+    if entry > control & TABLE_SIZE {
+        return Err(MsiError::NoSuchEntry);
+    }
+    let table = config::read_u32(function, capability.offset + 4);
+    let bar_base = match device.bars.get((table & 0b111) as usize) {
+        Some(Bar::Memory { addr, .. }) => *addr,
+        _ => return Err(MsiError::Table),
+    };
+    let entry_addr = PhysAddr::new(bar_base.as_u64() + u64::from(table & !0b111) + u64::from(entry) * ENTRY_SIZE);
+    let registers = crate::memory::map_mmio(entry_addr, ENTRY_SIZE).map_err(|_| MsiError::Table)?.as_mut_ptr::<u32>();
+    unsafe {
+        core::ptr::write_volatile(registers, address as u32);
+        core::ptr::write_volatile(registers.add(1), (address >> 32) as u32);
+        core::ptr::write_volatile(registers.add(2), data);
+        // clearing the vector control's mask bit unmasks the entry
+        core::ptr::write_volatile(registers.add(3), 0);
+    }
+    config::write_u16(function, capability.offset + 2, (control & !FUNCTION_MASK) | ENABLE);
+    device.set_command(device.command() | COMMAND_INTX_DISABLE);
+    Ok(())
+}
@@ -0,0 +1,192 @@
+//! Per-process page tables
+//!
+//! Every address space gets its own level 4 table. The kernel's level 4
+//! entries are copied into it, so the kernel half is shared (and stays
+//! supervisor-only), while the user region has page tables of its own.
+
+use x86_64::structures::paging::{
+    mapper::{MapToError, TranslateResult},
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+use crate::memory;
+
+/// First address user programs can use
+pub const USER_START: VirtAddr = VirtAddr::new_truncate(0x0000_1000_0000_0000);
+
+/// End of the user region, exclusive
+pub const USER_END: VirtAddr = VirtAddr::new_truncate(0x0000_2000_0000_0000);
+
+#[derive(Debug)]
+pub enum MapError {
+    /// The range is not inside the user region
+    OutsideUserRegion,
+    /// Part of the range is not mapped
+    NotMapped,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        MapError::Map(err)
+    }
+}
+
+/// Frame allocator that hands out zeroed frames from the global allocator
+struct ZeroedFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for ZeroedFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = memory::allocate_frame()?;
+        let ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { core::ptr::write_bytes(ptr, 0, 4096) };
+        Some(frame)
+    }
+}
+
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel mapped and an empty user region
+    pub fn new() -> Result<Self, MapError> {
+        let kernel_frame = *memory::KERNEL_LEVEL_4_TABLE.try_get().expect("memory::install not called");
+        let level_4_frame = ZeroedFrames.allocate_frame().ok_or(MapToError::<Size4KiB>::FrameAllocationFailed)?;
+
+        let kernel_table: &PageTable = unsafe { &*memory::phys_to_virt(kernel_frame.start_address()).as_ptr() };
+        let table: &mut PageTable = unsafe { &mut *memory::phys_to_virt(level_4_frame.start_address()).as_mut_ptr() };
+        let user_entries = u64::from(Page::<Size4KiB>::containing_address(USER_START).p4_index())
+            ..u64::from(Page::<Size4KiB>::containing_address(USER_END).p4_index());
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if entry.is_unused() {
+                continue;
+            }
+            assert!(!user_entries.contains(&(index as u64)), "kernel mapped in the user region");
+            table[index] = entry.clone();
+        }
+
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        // the table is only ever borrowed for the duration of a method call
+        unsafe { memory::mapper_for(self.level_4_frame) }
+    }
+
+    /// Maps zeroed pages covering `start..start + len` in the user region
+    ///
+    /// `flags` are added to `PRESENT | USER_ACCESSIBLE`. Pages that are
+    /// already mapped keep their contents and become writable or executable
+    /// if either mapping is, since ELF segments may share a page.
+    pub fn map(&mut self, start: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        if len == 0 {
+            return Ok(());
+        }
+        if !is_user_range(start, len) {
+            return Err(MapError::OutsideUserRegion);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (len - 1));
+
+        let mut mapper = self.mapper();
+        for page in Page::range_inclusive(first, last) {
+            if let TranslateResult::Mapped { flags: existing, .. } = mapper.translate(page.start_address()) {
+                let mut merged = existing | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                unsafe {
+                    mapper.update_flags(page, merged).map_err(|_| MapError::NotMapped)?.flush();
+                }
+                continue;
+            }
+            let frame = ZeroedFrames.allocate_frame().ok_or(MapToError::<Size4KiB>::FrameAllocationFailed)?;
+            unsafe {
+                mapper.map_to_with_table_flags(page, frame, flags, parent_flags, &mut ZeroedFrames)?.flush();
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to `addr`, which must already be mapped
+    ///
+    /// Works whether or not this address space is active.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < data.len() {
+            let target = addr + written;
+            let phys = mapper.translate_addr(target).ok_or(MapError::NotMapped)?;
+            let in_page = 4096 - usize::from(target.page_offset());
+            let chunk = in_page.min(data.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    memory::phys_to_virt(phys).as_mut_ptr(),
+                    chunk,
+                );
+            }
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    /// Copies from `addr`, which must be mapped, into `buf`
+    ///
+    /// Works whether or not this address space is active.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), MapError> {
+        let mapper = self.mapper();
+        let mut read = 0;
+        while read < buf.len() {
+            let source = addr + read;
+            let phys = mapper.translate_addr(source).ok_or(MapError::NotMapped)?;
+            let in_page = 4096 - usize::from(source.page_offset());
+            let chunk = in_page.min(buf.len() - read);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    memory::phys_to_virt(phys).as_ptr(),
+                    buf[read..].as_mut_ptr(),
+                    chunk,
+                );
+            }
+            read += chunk;
+        }
+        Ok(())
+    }
+
+    /// Returns true if `start..start + len` lies in the user region and is
+    /// mapped user accessible (and writable, if `writable` is set)
+    pub fn is_accessible(&self, start: VirtAddr, len: u64, writable: bool) -> bool {
+        if len == 0 {
+            return true;
+        }
+        if !is_user_range(start, len) {
+            return false;
+        }
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if writable {
+            required |= PageTableFlags::WRITABLE;
+        }
+        let mapper = self.mapper();
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (len - 1));
+        Page::range_inclusive(first, last).all(|page| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(required),
+            _ => false,
+        })
+    }
+}
+
+/// Returns true if `start..start + len` lies in the user region
+pub fn is_user_range(start: VirtAddr, len: u64) -> bool {
+    start >= USER_START
+        && start.as_u64().checked_add(len).map_or(false, |end| end <= USER_END.as_u64())
+}
@@ -0,0 +1,292 @@
+//! ELF64 parser and program loader
+//!
+//! Only statically linked x86_64 executables (`ET_EXEC`) are supported.
+//! Their `PT_LOAD` segments must lie in the user region, see
+//! `address_space::USER_START`.
+
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use super::address_space::{self, AddressSpace, MapError};
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// Auxiliary vector entry types passed on the initial stack
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum ElfError {
+    /// The file ends before a header or segment it describes
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    /// Not an `ET_EXEC` executable, e.g. a shared object
+    NotExecutable,
+    NotX86_64,
+    BadProgramHeaderSize,
+    /// A segment is larger in the file than in memory, or overflows
+    BadSegment,
+    /// A loadable segment lies outside the user region
+    SegmentOutsideUserRegion,
+    /// The entry point is not in an executable segment
+    BadEntry,
+    NoLoadableSegments,
+    Map(MapError),
+}
+
+impl From<MapError> for ElfError {
+    fn from(err: MapError) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr - self.vaddr < self.mem_size
+    }
+}
+
+/// A validated ELF64 executable
+#[derive(Debug)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_header_offset: u64,
+    program_header_count: u16,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl<'a> ElfFile<'a> {
+    /// Validates the file header and all program headers
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 {
+            return Err(ElfError::NotElf64);
+        }
+        if data[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if data[6] != VERSION_CURRENT || read_u32(data, 20)? != u32::from(VERSION_CURRENT) {
+            return Err(ElfError::BadVersion);
+        }
+        if read_u16(data, 16)? != TYPE_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18)? != MACHINE_X86_64 {
+            return Err(ElfError::NotX86_64);
+        }
+        if usize::from(read_u16(data, 54)?) != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaderSize);
+        }
+
+        let elf = ElfFile {
+            data,
+            entry: read_u64(data, 24)?,
+            program_header_offset: read_u64(data, 32)?,
+            program_header_count: read_u16(data, 56)?,
+        };
+
+        let table_size = u64::from(elf.program_header_count) * PROGRAM_HEADER_SIZE as u64;
+        match elf.program_header_offset.checked_add(table_size) {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::Truncated),
+        }
+
+        let mut loadable = false;
+        let mut entry_ok = false;
+        for header in elf.program_headers() {
+            if header.kind != PT_LOAD {
+                continue;
+            }
+            loadable = true;
+            if header.file_size > header.mem_size {
+                return Err(ElfError::BadSegment);
+            }
+            match header.offset.checked_add(header.file_size) {
+                Some(end) if end <= data.len() as u64 => {}
+                _ => return Err(ElfError::Truncated),
+            }
+            let start = VirtAddr::try_new(header.vaddr).map_err(|_| ElfError::SegmentOutsideUserRegion)?;
+            if !address_space::is_user_range(start, header.mem_size) {
+                return Err(ElfError::SegmentOutsideUserRegion);
+            }
+            if header.flags & PF_X != 0 && header.contains(elf.entry) {
+                entry_ok = true;
+            }
+        }
+        if !loadable {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        if !entry_ok {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..usize::from(self.program_header_count)).map(move |index| {
+            let base = self.program_header_offset as usize + index * PROGRAM_HEADER_SIZE;
+            // bounds were checked in `parse`
+            let data = self.data;
+            ProgramHeader {
+                kind: read_u32(data, base).unwrap(),
+                flags: read_u32(data, base + 4).unwrap(),
+                offset: read_u64(data, base + 8).unwrap(),
+                vaddr: read_u64(data, base + 16).unwrap(),
+                file_size: read_u64(data, base + 32).unwrap(),
+                mem_size: read_u64(data, base + 40).unwrap(),
+                align: read_u64(data, base + 48).unwrap(),
+            }
+        })
+    }
+
+    /// Address of the program headers once loaded, if they are part of a segment
+    fn program_headers_addr(&self) -> Option<u64> {
+        if let Some(phdr) = self.program_headers().find(|header| header.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+            .find(|header| {
+                header.offset <= self.program_header_offset
+                    && self.program_header_offset - header.offset < header.file_size
+            })
+            .map(|header| header.vaddr + (self.program_header_offset - header.offset))
+    }
+
+    /// Maps the loadable segments into `address_space` and copies their contents
+    ///
+    /// Memory past the end of a segment's file contents (`.bss`) is zeroed.
+    pub fn load(&self, address_space: &mut AddressSpace) -> Result<(), ElfError> {
+        for header in self.program_headers().filter(|header| header.kind == PT_LOAD) {
+            let start = VirtAddr::new(header.vaddr);
+            address_space.map(start, header.mem_size, header.page_flags())?;
+            let contents = &self.data[header.offset as usize..(header.offset + header.file_size) as usize];
+            address_space.write(start, contents)?;
+            log::trace!("loaded segment {:?}", header);
+        }
+        Ok(())
+    }
+
+    /// Builds the initial user stack below `stack_top` as described by the
+    /// System V ABI and returns the stack pointer to start the program with
+    ///
+    /// From the stack pointer upwards: `argc`, the `argv` pointers, a null
+    /// pointer, the `envp` pointers, a null pointer, the auxiliary vector
+    /// terminated by `AT_NULL`, and then the strings themselves.
+    pub fn init_stack(
+        &self,
+        address_space: &mut AddressSpace,
+        stack_top: VirtAddr,
+        args: &[&str],
+        env: &[&str],
+    ) -> Result<VirtAddr, ElfError> {
+        let mut strings = Vec::new();
+        let mut offsets = Vec::with_capacity(args.len() + env.len());
+        for string in args.iter().chain(env.iter()) {
+            offsets.push(strings.len() as u64);
+            strings.extend_from_slice(string.as_bytes());
+            strings.push(0);
+        }
+        let strings_start = (stack_top - strings.len() as u64).align_down(16u64);
+
+        let mut auxv = Vec::new();
+        if let Some(phdr) = self.program_headers_addr() {
+            auxv.extend_from_slice(&[AT_PHDR, phdr]);
+        }
+        auxv.extend_from_slice(&[
+            AT_PHENT, PROGRAM_HEADER_SIZE as u64,
+            AT_PHNUM, u64::from(self.program_header_count),
+            AT_PAGESZ, 4096,
+            AT_ENTRY, self.entry,
+            AT_NULL, 0,
+        ]);
+
+        let mut words = Vec::with_capacity(1 + offsets.len() + 2 + auxv.len());
+        words.push(args.len() as u64);
+        let (arg_offsets, env_offsets) = offsets.split_at(args.len());
+        words.extend(arg_offsets.iter().map(|offset| strings_start.as_u64() + offset));
+        words.push(0);
+        words.extend(env_offsets.iter().map(|offset| strings_start.as_u64() + offset));
+        words.push(0);
+        words.extend_from_slice(&auxv);
+
+        // the stack pointer must be 16 byte aligned at the entry point
+        let stack_pointer = (strings_start - words.len() as u64 * 8).align_down(16u64);
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        address_space.write(stack_pointer, &bytes)?;
+        address_space.write(strings_start, &strings)?;
+        Ok(stack_pointer)
+    }
+}
@@ -0,0 +1,263 @@
+//! Ring 3 user mode processes
+//!
+//! A process is a kernel thread with its own address space that drops to
+//! ring 3 to run its program, and comes back to the kernel through
+//! interrupts and the `syscall` instruction, see `syscall`.
+
+use alloc::{borrow::Cow, collections::BTreeMap, vec::Vec};
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::thread::{self, ThreadId};
+
+pub mod address_space;
+pub mod elf;
+pub mod syscall;
+
+pub use address_space::{AddressSpace, MapError, USER_START, USER_END};
+pub use elf::{ElfFile, ElfError};
+use syscall::SyscallError;
+
+/// Where flat binaries passed to `spawn` are loaded and started
+pub const USER_CODE_START: VirtAddr = USER_START;
+
+/// The user stack grows down from the end of the user region
+pub const USER_STACK_TOP: VirtAddr = USER_END;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+/// `mmap` hands out regions upwards from here
+const MMAP_START: VirtAddr = VirtAddr::new_truncate(0x0000_1800_0000_0000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ProcessId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+struct Process {
+    id: ProcessId,
+    name: Cow<'static, str>,
+    /// `None` while `mmap` maps into it with the table unlocked
+    address_space: Option<AddressSpace>,
+    /// Start of the next `mmap` region
+    next_mmap: VirtAddr,
+}
+
+lazy_static! {
+    /// Processes by the thread running them
+    ///
+    /// Only locked in thread context with interrupts enabled, so it may
+    /// allocate. The page fault handler leaves faulted processes to `reap`.
+    static ref PROCESSES: Mutex<BTreeMap<ThreadId, Process>> = Mutex::new(BTreeMap::new());
+
+    /// Exit codes of the processes that exited, until `try_wait` takes them
+    static ref EXIT_CODES: Mutex<BTreeMap<ProcessId, i64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Sets up the syscall entry path, must be called after `gdt::init`
+pub fn init() {
+    syscall::init();
+}
+
+/// Starts a process running the flat binary `code`
+///
+/// The code is loaded at `USER_CODE_START` and started there, with a
+/// `USER_STACK_SIZE` stack below `USER_STACK_TOP`.
+pub fn spawn(name: impl Into<Cow<'static, str>>, code: &[u8]) -> Result<ProcessId, MapError> {
+    let mut address_space = AddressSpace::new()?;
+    address_space.map(USER_CODE_START, code.len() as u64, PageTableFlags::WRITABLE)?;
+    address_space.write(USER_CODE_START, code)?;
+    map_stack(&mut address_space)?;
+    Ok(start(name, address_space, USER_CODE_START, USER_STACK_TOP))
+}
+
+/// Starts a process running the ELF executable `data`
+///
+/// `args` and `env` are passed to it on the stack, like on Linux.
+pub fn spawn_elf(
+    name: impl Into<Cow<'static, str>>,
+    data: &[u8],
+    args: &[&str],
+    env: &[&str],
+) -> Result<ProcessId, ElfError> {
+    let elf = ElfFile::parse(data)?;
+    let mut address_space = AddressSpace::new()?;
+    elf.load(&mut address_space)?;
+    map_stack(&mut address_space)?;
+    let stack_pointer = elf.init_stack(&mut address_space, USER_STACK_TOP, args, env)?;
+    Ok(start(name, address_space, elf.entry(), stack_pointer))
+}
+
+/// Maps the `USER_STACK_SIZE` stack below `USER_STACK_TOP`
+pub fn map_stack(address_space: &mut AddressSpace) -> Result<(), MapError> {
+    address_space.map(
+        USER_STACK_TOP - USER_STACK_SIZE,
+        USER_STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+}
+
+/// Starts running `address_space` at `entry` with the given stack pointer
+pub fn start(
+    name: impl Into<Cow<'static, str>>,
+    address_space: AddressSpace,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+) -> ProcessId {
+    let id = ProcessId::new();
+    let name = name.into();
+    let level_4_frame = address_space.level_4_frame();
+    let thread = thread::spawn(name.clone(), move || {
+        // the thread may run before `start` registers it, its syscalls need the process
+        while with_current(|_| ()).is_none() {
+            thread::yield_now();
+        }
+        unsafe {
+            thread::set_address_space(level_4_frame);
+            enter_user_mode(entry, stack_pointer)
+        }
+    });
+    reap();
+    PROCESSES.lock().insert(thread, Process {
+        id,
+        name,
+        address_space: Some(address_space),
+        next_mmap: MMAP_START,
+    });
+    log::debug!("started process {} on thread {}", id, thread);
+    id
+}
+
+/// Drops to ring 3 and continues at `entry` with the stack pointer `stack`
+///
+/// This function is unsafe because the caller must guarantee that the
+/// active address space maps `entry` and the stack user accessible.
+unsafe fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let selectors = crate::gdt::selectors();
+    asm!(
+        "push {ss}",
+        "push {rsp}",
+        // interrupts enabled, plus the always set reserved bit 1
+        "push 0x202",
+        "push {cs}",
+        "push {rip}",
+        "iretq",
+        ss = in(reg) u64::from(selectors.user_data_selector.0),
+        rsp = in(reg) stack.as_u64(),
+        cs = in(reg) u64::from(selectors.user_code_selector.0),
+        rip = in(reg) entry.as_u64(),
+        options(noreturn),
+    );
+}
+
+/// Calls `f` with the process running on the current thread
+fn with_current<T>(f: impl FnOnce(&mut Process) -> T) -> Option<T> {
+    let thread = thread::current()?;
+    PROCESSES.lock().get_mut(&thread).map(f)
+}
+
+/// Returns true if the current process may access `start..start + len`
+pub(crate) fn is_accessible(start: VirtAddr, len: u64, writable: bool) -> bool {
+    with_current(|process| {
+        process.address_space.as_ref().map_or(false, |space| space.is_accessible(start, len, writable))
+    }).unwrap_or(false)
+}
+
+/// Maps `len` bytes of zeroed, writable memory into the current process
+pub(crate) fn mmap(len: u64) -> Result<VirtAddr, SyscallError> {
+    if len == 0 {
+        return Err(SyscallError::Invalid);
+    }
+    // only the process' own thread maps into it, so nothing else sees it taken out
+    let (start, mut address_space) = with_current(|process| {
+        process.address_space.take().map(|space| (process.next_mmap, space))
+    }).flatten().ok_or(SyscallError::Invalid)?;
+
+    // mapping allocates page tables, so it happens with the table unlocked
+    let mapped = start.as_u64().checked_add(len)
+        // keep clear of the stack, which is at the end of the user region
+        .filter(|&end| end <= (USER_STACK_TOP - USER_STACK_SIZE).as_u64())
+        .ok_or(SyscallError::NoMemory)
+        .and_then(|end| {
+            address_space
+                .map(start, len, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+                .map(|()| end)
+                .map_err(|_| SyscallError::NoMemory)
+        });
+
+    with_current(|process| {
+        process.address_space = Some(address_space);
+        let end = mapped?;
+        process.next_mmap = VirtAddr::new(end).align_up(4096u64);
+        Ok(start)
+    }).unwrap_or(Err(SyscallError::Invalid))
+}
+
+/// Terminates the current process
+///
+/// The frames of its address space are not reclaimed, since the boot
+/// frame allocator cannot free.
+///
+/// Must be called in thread context with interrupts enabled, see `kill`.
+pub(crate) fn exit(code: i64) -> ! {
+    if let Some(thread) = thread::current() {
+        let process = PROCESSES.lock().remove(&thread);
+        if let Some(process) = process {
+            log::info!("process {} ({}) exited with {}", process.id, process.name, code);
+            EXIT_CODES.lock().insert(process.id, code);
+        }
+    }
+    thread::exit();
+}
+
+/// Terminates the current process from the page fault handler
+///
+/// Only its thread is marked exited, since the handler runs with interrupts
+/// disabled and must not allocate or free. `reap` collects the process
+/// later, with exit code -1.
+pub(crate) fn kill() -> ! {
+    thread::exit();
+}
+
+/// Cleans up the processes whose thread was terminated by `kill`
+fn reap() {
+    let killed: Vec<Process> = {
+        let mut processes = PROCESSES.lock();
+        let threads: Vec<ThreadId> = processes.keys()
+            .filter(|thread| !thread::is_alive(**thread))
+            .copied()
+            .collect();
+        threads.iter().filter_map(|thread| processes.remove(thread)).collect()
+    };
+    for process in killed {
+        log::info!("process {} ({}) was killed", process.id, process.name);
+        EXIT_CODES.lock().insert(process.id, -1);
+    }
+}
+
+/// Returns the exit code of process `id` once it has exited, a process
+/// killed by a fault exits with -1
+///
+/// The code is only returned once.
+pub fn try_wait(id: ProcessId) -> Option<i64> {
+    reap();
+    EXIT_CODES.lock().remove(&id)
+}
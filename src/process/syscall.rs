@@ -0,0 +1,180 @@
+//! `syscall`/`sysret` entry path and the syscall table
+//!
+//! Calling convention (the same as Linux on x86_64): the syscall number is
+//! passed in `rax`, arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`,
+//! and the result is returned in `rax`. Negative results are errors, see
+//! `SyscallError`. All registers except `rax`, `rcx` and `r11` are preserved.
+//!
+//! | number | name  | arguments             | result                       |
+//! |--------|-------|-----------------------|------------------------------|
+//! | 0      | write | fd, buf, len          | bytes written                |
+//! | 1      | exit  | code                  | does not return              |
+//! | 2      | yield |                       | 0                            |
+//! | 3      | sleep | ticks                 | 0                            |
+//! | 4      | mmap  | len                   | address of the zeroed region |
+//!
+//! `write` supports fd 1 (the screen) and fd 2 (the serial port).
+
+use core::arch::global_asm;
+use num_enum::FromPrimitive;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::gdt;
+use crate::smp::percpu;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, FromPrimitive)]
+#[repr(u64)]
+pub enum Syscall {
+    Write = 0,
+    Exit,
+    Yield,
+    Sleep,
+    Mmap,
+    #[num_enum(default)]
+    Unknown,
+}
+
+/// Errors are returned to user mode as their negated value
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// No syscall with this number
+    NoSys = 1,
+    /// A pointer argument is not mapped in the process
+    Fault,
+    /// An argument is out of range, e.g. an unknown fd
+    Invalid,
+    /// Out of memory or address space
+    NoMemory,
+}
+
+/// Registers saved by `syscall_entry`, in the order they are on the stack
+#[derive(Debug)]
+#[repr(C)]
+#[allow(dead_code)]
+struct SyscallFrame {
+    number: u64,
+    args: [u64; 6],
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // interrupts are masked by SFMASK until the user state is saved.
+    // `swapgs` reaches the per-CPU data through the kernel GS base, whatever
+    // user mode left in GS. The kernel stack is the running thread's stack,
+    // set by the scheduler
+    "swapgs",
+    "mov gs:[{user_rsp}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "push qword ptr gs:[{user_rsp}]",
+    "swapgs",
+    "push rcx",
+    "push r11",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    // the user stack pointer is restored below, no interrupts on it in ring 0
+    "cli",
+    "add rsp, 8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    user_rsp = const percpu::USER_RSP_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    dispatch = sym dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables the `syscall` instruction and points it at `syscall_entry`
+///
+/// Must be called after `gdt::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    ).expect("GDT layout does not match what syscall/sysret expect");
+    LStar::write(VirtAddr::new(syscall_entry as u64));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) -> u64 {
+    // the kernel stack of the thread is in use now, so it can be preempted
+    x86_64::instructions::interrupts::enable();
+
+    let [a0, a1, a2, ..] = frame.args;
+    let result = match Syscall::from(frame.number) {
+        Syscall::Write => sys_write(a0, a1, a2),
+        Syscall::Exit => super::exit(a0 as i64),
+        Syscall::Yield => {
+            crate::thread::yield_now();
+            Ok(0)
+        }
+        Syscall::Sleep => {
+            sys_sleep(a0);
+            Ok(0)
+        }
+        Syscall::Mmap => super::mmap(a0).map(|addr| addr.as_u64()),
+        Syscall::Unknown => {
+            log::debug!("unknown syscall {} from {:#x}", frame.number, frame.rip);
+            Err(SyscallError::NoSys)
+        }
+    };
+    match result {
+        Ok(value) => value,
+        Err(err) => (-(err as i64)) as u64,
+    }
+}
+
+fn sys_write(fd: u64, buf: u64, len: u64) -> Result<u64, SyscallError> {
+    let buf = VirtAddr::try_new(buf).map_err(|_| SyscallError::Fault)?;
+    if !super::is_accessible(buf, len, false) {
+        return Err(SyscallError::Fault);
+    }
+    // the process' address space is active while it is in a syscall
+    let bytes = unsafe { core::slice::from_raw_parts(buf.as_ptr::<u8>(), len as usize) };
+    let text = core::str::from_utf8(bytes).unwrap_or("<invalid utf-8>");
+    match fd {
+        1 => crate::print!("{}", text),
+        2 => crate::serial_print!("{}", text),
+        _ => return Err(SyscallError::Invalid),
+    }
+    Ok(len)
+}
+
+fn sys_sleep(ticks: u64) {
+    let until = (crate::time::get() as u64).saturating_add(ticks);
+    while (crate::time::get() as u64) < until {
+        if crate::thread::has_ready_threads() {
+            crate::thread::yield_now();
+        } else {
+            x86_64::instructions::hlt();
+        }
+    }
+}
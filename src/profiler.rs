@@ -0,0 +1,222 @@
+//! Sampling profiler driven by the timer interrupt
+//!
+//! While enabled, every timer tick records the interrupted instruction and
+//! the task the executor was polling into fixed-size tables, without locks
+//! or allocation. `stop` resolves the samples to functions with the symbol
+//! table embedded by `build.rs` and attributes them to tasks. Only the CPU
+//! receiving the PIC timer, the bootstrap processor, is sampled.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::backtrace::SymbolTable;
+
+/// Distinct instruction addresses that can be recorded
+const ADDRESS_SLOTS: usize = 4096;
+/// Distinct tasks that can be recorded
+const TASK_SLOTS: usize = 256;
+/// Slots tried before a sample is dropped
+const MAX_PROBES: usize = 32;
+
+/// Open addressing hash table of sample counts, safe to update from
+/// interrupt handlers
+struct Histogram<const N: usize> {
+    /// Key + 1, 0 marks a free slot
+    keys: [AtomicU64; N],
+    counts: [AtomicU64; N],
+    dropped: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            keys: [ZERO; N],
+            counts: [ZERO; N],
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, key: u64) {
+        let stored = key.wrapping_add(1);
+        // Fibonacci hashing spreads nearby addresses over the table
+        let start = (stored.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % N;
+        for probe in 0..MAX_PROBES.min(N) {
+            let slot = (start + probe) % N;
+            let current = match self.keys[slot].compare_exchange(0, stored, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => stored,
+                Err(current) => current,
+            };
+            if current == stored {
+                self.counts[slot].fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn clear(&self) {
+        for (key, count) in self.keys.iter().zip(self.counts.iter()) {
+            key.store(0, Ordering::Relaxed);
+            count.store(0, Ordering::Relaxed);
+        }
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+
+    /// Keys and their counts
+    fn entries(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.keys.iter().zip(self.counts.iter()).filter_map(|(key, count)| {
+            match key.load(Ordering::Acquire) {
+                0 => None,
+                key => Some((key - 1, count.load(Ordering::Relaxed))),
+            }
+        })
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ADDRESSES: Histogram<ADDRESS_SLOTS> = Histogram::new();
+static TASKS: Histogram<TASK_SLOTS> = Histogram::new();
+static SAMPLES: AtomicU64 = AtomicU64::new(0);
+/// Samples taken while no task was polled: threads, idle, interrupts
+static NO_TASK: AtomicU64 = AtomicU64::new(0);
+/// Samples taken in user mode, not resolved to symbols
+static USER: AtomicU64 = AtomicU64::new(0);
+
+/// Called by the timer interrupt handler
+///
+/// Must not block or allocate
+pub(crate) fn sample(stack_frame: &InterruptStackFrame) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    SAMPLES.fetch_add(1, Ordering::Relaxed);
+    if stack_frame.code_segment & 3 == 3 {
+        USER.fetch_add(1, Ordering::Relaxed);
+    } else {
+        ADDRESSES.record(stack_frame.instruction_pointer.as_u64());
+    }
+    match crate::smp::percpu::try_current().and_then(|cpu| cpu.current_task()) {
+        Some(task) => TASKS.record(task),
+        None => {
+            NO_TASK.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Clears the previous samples and starts sampling
+pub fn start() {
+    ENABLED.store(false, Ordering::SeqCst);
+    ADDRESSES.clear();
+    TASKS.clear();
+    SAMPLES.store(0, Ordering::Relaxed);
+    NO_TASK.store(0, Ordering::Relaxed);
+    USER.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops sampling and summarizes the samples
+pub fn stop() -> Report {
+    ENABLED.store(false, Ordering::SeqCst);
+    report()
+}
+
+/// Samples of a function or task
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub samples: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub samples: u64,
+    /// Samples that found the tables full
+    pub dropped: u64,
+    pub user: u64,
+    /// Kernel samples per function, most first; addresses without a
+    /// symbol are grouped as `<unknown>`
+    pub functions: Vec<Entry>,
+    /// Samples per task, most first, including `<no task>`
+    pub tasks: Vec<Entry>,
+}
+
+/// Summarizes the samples so far, also while sampling
+pub fn report() -> Report {
+    let symbols = SymbolTable::kernel();
+    let mut functions = BTreeMap::<&str, u64>::new();
+    for (address, count) in ADDRESSES.entries() {
+        let name = symbols.lookup(address).map_or("<unknown>", |symbol| symbol.name);
+        *functions.entry(name).or_default() += count;
+    }
+
+    let names: BTreeMap<u64, String> = crate::task::info::list()
+        .into_iter()
+        .map(|task| (task.id.as_u64(), task.name))
+        .collect();
+    let mut tasks: Vec<Entry> = TASKS.entries()
+        .map(|(id, samples)| Entry {
+            name: names.get(&id).cloned().unwrap_or_else(|| alloc::format!("task {}", id)),
+            samples,
+        })
+        .collect();
+    let no_task = NO_TASK.load(Ordering::Relaxed);
+    if no_task > 0 {
+        tasks.push(Entry { name: "<no task>".into(), samples: no_task });
+    }
+    tasks.sort_by(|a, b| b.samples.cmp(&a.samples));
+
+    let mut functions: Vec<Entry> = functions.into_iter()
+        .map(|(name, samples)| Entry { name: name.into(), samples })
+        .collect();
+    functions.sort_by(|a, b| b.samples.cmp(&a.samples));
+
+    Report {
+        samples: SAMPLES.load(Ordering::Relaxed),
+        dropped: ADDRESSES.dropped.load(Ordering::Relaxed) + TASKS.dropped.load(Ordering::Relaxed),
+        user: USER.load(Ordering::Relaxed),
+        functions,
+        tasks,
+    }
+}
+
+/// Functions listed by `Report`'s `Display`
+const TOP_FUNCTIONS: usize = 20;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |samples: u64| samples * 100 / self.samples.max(1);
+        writeln!(f, "{} samples, {} in user mode, {} dropped", self.samples, self.user, self.dropped)?;
+        writeln!(f, "top functions:")?;
+        for entry in self.functions.iter().take(TOP_FUNCTIONS) {
+            writeln!(f, "{:>6} {:>3}% {}", entry.samples, percent(entry.samples), entry.name)?;
+        }
+        writeln!(f, "tasks:")?;
+        for entry in &self.tasks {
+            writeln!(f, "{:>6} {:>3}% {}", entry.samples, percent(entry.samples), entry.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_histogram() {
+    static HISTOGRAM: Histogram<4> = Histogram::new();
+    for key in [0, 7, 7, 9, 11, 13].iter() {
+        HISTOGRAM.record(*key);
+    }
+    let mut entries: Vec<(u64, u64)> = HISTOGRAM.entries().collect();
+    entries.sort();
+    // the fifth key doesn't fit
+    assert_eq!(entries.len(), 4);
+    assert_eq!(HISTOGRAM.dropped.load(Ordering::Relaxed), 1);
+    assert!(entries.contains(&(0, 1)));
+    assert!(entries.contains(&(7, 2)));
+    HISTOGRAM.clear();
+    assert_eq!(HISTOGRAM.entries().count(), 0);
+}
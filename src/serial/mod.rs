@@ -0,0 +1,208 @@
+//! Serial ports COM1-COM4
+//!
+//! Ports are probed on first use and set up with 115200 8N1 until
+//! `configure` changes that. Output is written per stream (kernel log,
+//! console, debug protocol), each of which can be routed to any port.
+//! Everything goes to COM1 by default.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub mod uart;
+
+pub use uart::{ConfigError, LineConfig, Parity, StopBits, Uart};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// Standard I/O port base
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// Standard IRQ, shared by COM1 and COM3, and by COM2 and COM4
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What is written to a serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialStream {
+    /// `serial_print!` and the kernel log
+    Log,
+    /// Mirror of the `Console` virtual terminal and its input
+    Console,
+    /// Debugger protocol
+    Debug,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    NotPresent(ComPort),
+    Config(ConfigError),
+}
+
+enum Slot {
+    Unprobed,
+    Absent,
+    Present(Uart),
+}
+
+static PORTS: [Mutex<Slot>; 4] = {
+    const UNPROBED: Mutex<Slot> = Mutex::new(Slot::Unprobed);
+    [UNPROBED; 4]
+};
+
+/// Port index of each stream, `NOT_ROUTED` discards the stream
+static ROUTES: [AtomicU8; 3] = {
+    const COM1: AtomicU8 = AtomicU8::new(0);
+    [COM1; 3]
+};
+const NOT_ROUTED: u8 = 0xff;
+
+/// Runs `f` on the port with interrupts disabled, probing it on first use
+fn with_port<R>(port: ComPort, f: impl FnOnce(&mut Uart) -> R) -> Result<R, SerialError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut slot = PORTS[port.index()].lock();
+        if let Slot::Unprobed = *slot {
+            let mut uart = unsafe { Uart::new(port.base()) };
+            let config = LineConfig::default();
+            *slot = if uart.probe(&config).expect("default line config is valid") {
+                uart.init(&config).expect("default line config is valid");
+                Slot::Present(uart)
+            } else {
+                Slot::Absent
+            };
+        }
+        match &mut *slot {
+            Slot::Present(uart) => Ok(f(uart)),
+            _ => Err(SerialError::NotPresent(port)),
+        }
+    })
+}
+
+/// Probes all ports and lets the receive interrupts of the present ones
+/// through the PIC
+///
+/// Must be called after the PICs are initialized.
+pub fn init() {
+    let mut mask: Port<u8> = Port::new(0x21);
+    for port in ComPort::ALL {
+        if is_present(port) {
+            unsafe {
+                let irqs = mask.read();
+                mask.write(irqs & !(1 << port.irq()));
+            }
+        }
+    }
+}
+
+pub fn is_present(port: ComPort) -> bool {
+    with_port(port, |_| ()).is_ok()
+}
+
+/// Changes line speed and framing of the port
+pub fn configure(port: ComPort, config: &LineConfig) -> Result<(), SerialError> {
+    with_port(port, |uart| uart.init(config))?.map_err(SerialError::Config)
+}
+
+/// Sends `stream` to `port`, or nowhere for `None`
+pub fn route(stream: SerialStream, port: Option<ComPort>) {
+    let index = port.map_or(NOT_ROUTED, |port| port.index() as u8);
+    ROUTES[stream as usize].store(index, Ordering::Relaxed);
+}
+
+/// Port the stream is written to
+pub fn route_of(stream: SerialStream) -> Option<ComPort> {
+    ComPort::ALL.get(usize::from(ROUTES[stream as usize].load(Ordering::Relaxed))).copied()
+}
+
+/// Writes to the port the stream is routed to, dropping the bytes if
+/// there is none
+pub fn write_bytes(stream: SerialStream, bytes: &[u8]) {
+    if let Some(port) = route_of(stream) {
+        with_port(port, |uart| {
+            for byte in bytes {
+                uart.send(*byte);
+            }
+        }).ok();
+    }
+}
+
+/// Reads a byte from the port the stream is routed to, if one was received
+///
+/// Polls the port directly, for code that runs with interrupts disabled,
+/// like the debugger.
+pub fn read_byte(stream: SerialStream) -> Option<u8> {
+    with_port(route_of(stream)?, |uart| uart.try_receive()).ok().flatten()
+}
+
+/// Reads the received bytes of all present ports on `irq`
+///
+/// Called by the serial interrupt handlers.
+pub(crate) fn receive_interrupt(irq: u8) {
+    for port in ComPort::ALL.iter().copied().filter(|port| port.irq() == irq) {
+        // the UART raises one interrupt for everything received until it is drained
+        while let Ok(Some(byte)) = with_port(port, |uart| uart.try_receive()) {
+            crate::task::serial::add_byte(port, byte);
+        }
+    }
+}
+
+/// Mirrors a byte written to the console, translating newlines for terminals
+pub(crate) fn console_write_byte(byte: u8) {
+    if byte == b'\n' {
+        write_bytes(SerialStream::Console, b"\r\n");
+    } else {
+        write_bytes(SerialStream::Console, &[byte]);
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if let Some(port) = route_of(SerialStream::Log) {
+        with_port(port, |uart| uart.write_fmt(args).expect("Printing to serial failed")).ok();
+    }
+}
+
+/// Prints to the host through the serial interface.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*));
+    };
+}
+
+/// Prints to the host through the serial interface, appending a newline.
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
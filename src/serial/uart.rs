@@ -0,0 +1,215 @@
+//! 16550 UART driver
+//!
+//! Only programmed I/O, one byte at a time; the receive interrupt is used to
+//! wake the reader, see `task::serial`.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+/// Divisor latch access bit in the line control register
+const DLAB: u8 = 1 << 7;
+const RECEIVED_DATA_AVAILABLE: u8 = 1;
+/// Enable and clear both FIFOs, interrupt at 14 bytes
+const FIFO_ENABLE: u8 = 0xc7;
+/// DTR, RTS and OUT2, which gates the interrupt line
+const MODEM_READY: u8 = 0x0b;
+const LOOPBACK: u8 = 1 << 4;
+
+const DATA_READY: u8 = 1;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// Clock of the baud rate generator divided by 16
+const BASE_BAUD: u32 = 115_200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always 1
+    Mark,
+    /// Parity bit always 0
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Line speed and framing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineConfig {
+    /// 115200 baud, 8N1
+    fn default() -> Self {
+        LineConfig {
+            baud: BASE_BAUD,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Not `115200 / n` for a 16 bit `n`
+    BadBaud(u32),
+    BadDataBits(u8),
+}
+
+impl LineConfig {
+    fn divisor(&self) -> Result<u16, ConfigError> {
+        if self.baud == 0 || BASE_BAUD % self.baud != 0 || BASE_BAUD / self.baud > u32::from(u16::MAX) {
+            return Err(ConfigError::BadBaud(self.baud));
+        }
+        Ok((BASE_BAUD / self.baud) as u16)
+    }
+
+    /// Time to send one character, in microseconds, rounded up
+    ///
+    /// Only valid once `divisor` accepted the baud rate.
+    fn character_time(&self) -> u32 {
+        let parity = if self.parity == Parity::None { 0 } else { 1 };
+        let stop = if self.stop_bits == StopBits::Two { 2 } else { 1 };
+        let bits = 1 + u32::from(self.data_bits) + parity + stop;
+        (bits * 1_000_000 + self.baud - 1) / self.baud
+    }
+
+    fn line_control(&self) -> Result<u8, ConfigError> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(ConfigError::BadDataBits(self.data_bits));
+        }
+        let mut value = self.data_bits - 5;
+        if self.stop_bits == StopBits::Two {
+            value |= 1 << 2;
+        }
+        value |= match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+        Ok(value)
+    }
+}
+
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    /// This function is unsafe because the caller must guarantee that
+    /// `base` is the I/O port base of a 16550 and not used elsewhere.
+    pub const unsafe fn new(base: u16) -> Uart {
+        Uart { base }
+    }
+
+    fn port(&self, register: u16) -> Port<u8> {
+        Port::new(self.base + register)
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { self.port(register).read() }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        unsafe { self.port(register).write(value) }
+    }
+
+    /// Checks that a working UART answers at the base port
+    ///
+    /// The scratch register has to keep a value, and a byte sent in loopback
+    /// mode with `config` has to come back within two character times.
+    /// Leaves the UART with interrupts disabled.
+    pub fn probe(&mut self, config: &LineConfig) -> Result<bool, ConfigError> {
+        self.write(SCRATCH, 0x5a);
+        if self.read(SCRATCH) != 0x5a {
+            return Ok(false);
+        }
+        self.set_line(config)?;
+        self.write(FIFO_CONTROL, FIFO_ENABLE);
+        self.write(MODEM_CONTROL, LOOPBACK | MODEM_READY);
+        self.write(DATA, 0xae);
+        let mut echoed = None;
+        for _ in 0..2 * config.character_time() {
+            if self.read(LINE_STATUS) & DATA_READY != 0 {
+                echoed = Some(self.read(DATA));
+                break;
+            }
+            io_delay();
+        }
+        self.write(MODEM_CONTROL, MODEM_READY);
+        Ok(echoed == Some(0xae))
+    }
+
+    /// Sets line speed and framing, and enables the FIFOs and the receive interrupt
+    pub fn init(&mut self, config: &LineConfig) -> Result<(), ConfigError> {
+        self.set_line(config)?;
+        self.write(FIFO_CONTROL, FIFO_ENABLE);
+        self.write(MODEM_CONTROL, MODEM_READY);
+        self.write(INTERRUPT_ENABLE, RECEIVED_DATA_AVAILABLE);
+        Ok(())
+    }
+
+    /// Programs the divisor and the line control register, with interrupts disabled
+    fn set_line(&mut self, config: &LineConfig) -> Result<(), ConfigError> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(LINE_CONTROL, DLAB);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, line_control);
+        Ok(())
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        while self.read(LINE_STATUS) & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read(LINE_STATUS) & DATA_READY != 0 {
+            Some(self.read(DATA))
+        } else {
+            None
+        }
+    }
+}
+
+/// Waits about a microsecond, the time an ISA bus write to the unused
+/// POST port 0x80 takes
+fn io_delay() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
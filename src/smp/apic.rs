@@ -0,0 +1,94 @@
+//! Local APIC, used to start the application processors and to send
+//! wakeup interrupts between CPUs
+//!
+//! Device interrupts still go through the legacy PICs to the bootstrap
+//! processor, see `interrupts`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::PhysAddr;
+use crate::interrupts::InterruptIndex;
+
+const ID: u64 = 0x20;
+const EOI: u64 = 0xb0;
+const SPURIOUS_VECTOR: u64 = 0xf0;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+
+/// Virtual address of the local APIC registers, 0 until `init` mapped them
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+fn register(offset: u64) -> *mut u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "local APIC not mapped");
+    (base + offset) as *mut u32
+}
+
+fn read(offset: u64) -> u32 {
+    unsafe { core::ptr::read_volatile(register(offset)) }
+}
+
+fn write(offset: u64, value: u32) {
+    unsafe { core::ptr::write_volatile(register(offset), value) }
+}
+
+/// Maps the local APIC registers, which are at the same address on every CPU
+pub fn init(addr: PhysAddr) -> Result<(), MapToError<Size4KiB>> {
+    let base = crate::memory::map_mmio(addr, 4096)?;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Enables the local APIC of the calling CPU, so it can receive IPIs
+pub fn enable() {
+    write(SPURIOUS_VECTOR, SOFTWARE_ENABLE | u32::from(InterruptIndex::Spurious.as_u8()));
+}
+
+/// APIC ID of the calling CPU
+pub fn id() -> u32 {
+    read(ID) >> 24
+}
+
+/// Signals the end of an interrupt delivered by the local APIC, i.e. an IPI
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+fn send(apic_id: u32, command: u32) {
+    // an IPI sent by an interrupt handler in between would change the destination
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write(ICR_HIGH, apic_id << 24);
+        // writing the low half sends the interrupt
+        write(ICR_LOW, command);
+        while read(ICR_LOW) & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Resets the CPU into the wait-for-SIPI state
+pub fn send_init(apic_id: u32) {
+    send(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Starts the CPU in real mode at `page * 4096`, which must be below 1 MiB
+pub fn send_startup(apic_id: u32, page: u8) {
+    send(apic_id, ICR_STARTUP | ICR_ASSERT | u32::from(page));
+}
+
+/// Sends the fixed interrupt `vector` to the CPU
+///
+/// Does not allocate, so wakers may use it in interrupt handlers.
+pub fn send_ipi(apic_id: u32, vector: InterruptIndex) {
+    send(apic_id, ICR_ASSERT | u32::from(vector.as_u8()));
+}
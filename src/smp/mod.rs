@@ -0,0 +1,182 @@
+//! Bring-up of the application processors
+//!
+//! The processors are found in the ACPI MADT and started one after another
+//! with INIT-SIPI-SIPI through the trampoline. Every CPU gets its own GDT,
+//! TSS and stacks, and its `PerCpu` data in the GS base. Each one then runs
+//! the function passed to `start_aps`, usually an executor with its own run
+//! queue. The thread scheduler only runs on the bootstrap processor.
+
+use alloc::vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::interrupts::InterruptIndex;
+
+pub mod apic;
+pub mod percpu;
+mod trampoline;
+
+pub use percpu::PerCpu;
+
+/// CPUs beyond this are not started
+pub const MAX_CPUS: usize = 64;
+
+/// Stack size of the application processors' boot threads
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// Timer ticks to wait for a started CPU to check in
+const STARTUP_TIMEOUT: usize = 2;
+
+static BSP: Once<PerCpu> = Once::new();
+
+/// Per-CPU data by CPU index, filled in by each CPU once it runs
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = {
+    const NONE: AtomicPtr<PerCpu> = AtomicPtr::new(ptr::null_mut());
+    [NONE; MAX_CPUS]
+};
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by a starting CPU once it no longer needs the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+static AP_MAIN: Once<fn() -> !> = Once::new();
+
+/// Installs the per-CPU data of the bootstrap processor
+///
+/// Must be the first thing `init` does, everything else may use `percpu::current`.
+pub fn init_bsp() {
+    let apic_id = unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24;
+    let bsp = BSP.call_once(|| PerCpu::new(0, apic_id));
+    CPUS[0].store(bsp as *const PerCpu as *mut PerCpu, Ordering::Release);
+    percpu::install(bsp);
+}
+
+/// Index of the calling CPU, 0 is the bootstrap processor
+pub fn current_id() -> usize {
+    percpu::try_current().map_or(0, |cpu| cpu.id)
+}
+
+/// Number of running CPUs
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+pub fn cpu(id: usize) -> Option<&'static PerCpu> {
+    let cpu = CPUS.get(id)?.load(Ordering::Acquire);
+    unsafe { cpu.as_ref() }
+}
+
+/// Wakes the CPU from `hlt`, e.g. because a task on its run queue was woken
+///
+/// Does not allocate, so it may be used in interrupt handlers.
+pub fn kick(id: usize) {
+    if !apic::is_initialized() || id == current_id() {
+        return;
+    }
+    if let Some(cpu) = cpu(id) {
+        apic::send_ipi(cpu.apic_id, InterruptIndex::Wakeup);
+    }
+}
+
+/// Wakes all other CPUs
+pub fn kick_all() {
+    for id in 0..MAX_CPUS {
+        kick(id);
+    }
+}
+
+fn wait_ticks(ticks: usize, done: impl Fn() -> bool) -> bool {
+    let until = crate::time::get() + ticks;
+    while crate::time::get() < until {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    done()
+}
+
+/// Starts every enabled processor listed in the MADT, each of which then runs `ap_main`
+///
+/// Must be called on the bootstrap processor with interrupts enabled, after
+/// `memory::install`. Returns the number of running CPUs.
+pub fn start_aps(rsdp: PhysAddr, ap_main: fn() -> !) -> usize {
+    let madt = match crate::acpi::madt(rsdp) {
+        Ok(madt) => madt,
+        Err(err) => {
+            log::warn!("no processor list, staying on one CPU: {:?}", err);
+            return cpu_count();
+        }
+    };
+    if let Err(err) = apic::init(madt.local_apic_address) {
+        log::warn!("couldn't map the local APIC, staying on one CPU: {:?}", err);
+        return cpu_count();
+    }
+    apic::enable();
+    AP_MAIN.call_once(|| ap_main);
+
+    let frame = match crate::memory::low_frame() {
+        Some(frame) => frame,
+        None => {
+            log::warn!("no memory below 1 MiB for the AP trampoline, staying on one CPU");
+            return cpu_count();
+        }
+    };
+    if let Err(err) = crate::memory::identity_map(frame) {
+        log::warn!("couldn't identity map the AP trampoline, staying on one CPU: {:?}", err);
+        return cpu_count();
+    }
+    let trampoline = unsafe { trampoline::Trampoline::install(frame) };
+    let level_4_frame = *crate::memory::KERNEL_LEVEL_4_TABLE.try_get().expect("memory::install not called");
+    let bsp_apic_id = percpu::current().apic_id;
+
+    for processor in madt.processors.iter().filter(|p| p.enabled && u32::from(p.apic_id) != bsp_apic_id) {
+        let id = cpu_count();
+        if id >= MAX_CPUS {
+            log::warn!("more than {} CPUs, ignoring the rest", MAX_CPUS);
+            break;
+        }
+        let apic_id = u32::from(processor.apic_id);
+        let cpu = PerCpu::leak(id, apic_id);
+        let stack = vec![0u8; AP_STACK_SIZE].leak();
+        let stack_top = (x86_64::VirtAddr::from_ptr(stack.as_ptr()) + AP_STACK_SIZE).align_down(16u64);
+        trampoline.prepare(level_4_frame, stack_top, ap_entry, cpu as *const PerCpu as u64);
+        AP_STARTED.store(false, Ordering::Release);
+
+        apic::send_init(apic_id);
+        // at least one full tick, the CPU needs 10ms to reset
+        wait_ticks(2, || false);
+        // the second startup IPI is only needed if the first one got lost
+        apic::send_startup(apic_id, trampoline.page());
+        let started = || AP_STARTED.load(Ordering::Acquire);
+        if !wait_ticks(1, started) {
+            apic::send_startup(apic_id, trampoline.page());
+            if !wait_ticks(STARTUP_TIMEOUT, started) {
+                log::warn!("CPU with APIC ID {} did not start", apic_id);
+                continue;
+            }
+        }
+        CPU_COUNT.store(id + 1, Ordering::Release);
+    }
+    log::info!("{} CPUs running", cpu_count());
+    cpu_count()
+}
+
+/// Called by the trampoline in long mode, on the stack set up for the CPU
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let cpu = unsafe { &*(cpu as *const PerCpu) };
+    percpu::install(cpu);
+    AP_STARTED.store(true, Ordering::Release);
+
+    crate::gdt::init_ap();
+    crate::interrupts::init_idt();
+    crate::process::init();
+    apic::enable();
+    CPUS[cpu.id].store(cpu as *const PerCpu as *mut PerCpu, Ordering::Release);
+    log::debug!("CPU {} (APIC ID {}) started", cpu.id, cpu.apic_id);
+
+    x86_64::instructions::interrupts::enable();
+    let ap_main = AP_MAIN.get().expect("no AP main function");
+    ap_main()
+}
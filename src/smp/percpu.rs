@@ -0,0 +1,137 @@
+//! Per-CPU data, reached through the GS base
+//!
+//! While in the kernel, `IA32_GS_BASE` points at the `PerCpu` of the CPU.
+//! `IA32_KERNEL_GS_BASE` always holds the same pointer, so the syscall entry
+//! can `swapgs` to reach it no matter what user mode did to GS. User mode
+//! can clear the GS base by loading a segment selector, `current` restores
+//! it in that case.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+use crate::task::TaskId;
+
+/// Offset of `PerCpu::user_rsp`, used by the syscall entry
+pub const USER_RSP_OFFSET: usize = 0;
+/// Offset of `PerCpu::kernel_stack`, used by the syscall entry
+pub const KERNEL_STACK_OFFSET: usize = 8;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Scratch slot for the user stack pointer on syscall entry,
+    /// only accessed from assembly
+    #[allow(dead_code)]
+    user_rsp: AtomicU64,
+    /// Stack the syscall entry switches to, the same as `rsp0` in the TSS
+    kernel_stack: AtomicU64,
+    /// Index of the CPU, 0 is the bootstrap processor
+    pub id: usize,
+    pub apic_id: u32,
+    tss: AtomicPtr<TaskStateSegment>,
+    /// Id of the task the executor is polling, `NO_TASK` between polls
+    current_task: AtomicU64,
+    /// TSC value at the start of the poll in progress, see `task::coop`
+    poll_start: AtomicU64,
+    /// Set while single-stepping code that had interrupts enabled, see `TrapFrame::begin_step`
+    step_masked_interrupts: AtomicBool,
+}
+
+const NO_TASK: u64 = u64::MAX;
+
+impl PerCpu {
+    pub const fn new(id: usize, apic_id: u32) -> PerCpu {
+        PerCpu {
+            user_rsp: AtomicU64::new(0),
+            kernel_stack: AtomicU64::new(0),
+            id,
+            apic_id,
+            tss: AtomicPtr::new(core::ptr::null_mut()),
+            current_task: AtomicU64::new(NO_TASK),
+            poll_start: AtomicU64::new(0),
+            step_masked_interrupts: AtomicBool::new(false),
+        }
+    }
+
+    /// Allocates the data of an application processor, it is never freed
+    pub fn leak(id: usize, apic_id: u32) -> &'static PerCpu {
+        Box::leak(Box::new(PerCpu::new(id, apic_id)))
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+
+    /// Sets the TSS loaded on this CPU, whose `rsp0` `set_kernel_stack` updates
+    pub fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::Relaxed);
+    }
+
+    /// Set by the executor around every poll, read by the profiler
+    pub fn set_current_task(&self, task: Option<TaskId>) {
+        self.current_task.store(task.map_or(NO_TASK, |task| task.as_u64()), Ordering::Relaxed);
+    }
+
+    /// Raw id of the task being polled on this CPU
+    pub fn current_task(&self) -> Option<u64> {
+        Some(self.current_task.load(Ordering::Relaxed)).filter(|id| *id != NO_TASK)
+    }
+
+    /// TSCs of different CPUs are not synchronized, so each keeps its own
+    pub(crate) fn set_poll_start(&self, tsc: u64) {
+        self.poll_start.store(tsc, Ordering::Relaxed);
+    }
+
+    pub(crate) fn poll_start(&self) -> u64 {
+        self.poll_start.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_step_masked_interrupts(&self, masked: bool) {
+        self.step_masked_interrupts.store(masked, Ordering::Relaxed);
+    }
+
+    pub(crate) fn take_step_masked_interrupts(&self) -> bool {
+        self.step_masked_interrupts.swap(false, Ordering::Relaxed)
+    }
+
+    /// Sets the stack used when entering the kernel from user mode,
+    /// by interrupts as well as by `syscall`
+    ///
+    /// This function is unsafe because the caller must guarantee that
+    /// `stack_end` is the end of a valid stack that is not in use.
+    pub unsafe fn set_kernel_stack(&self, stack_end: VirtAddr) {
+        let tss = self.tss.load(Ordering::Relaxed);
+        if !tss.is_null() {
+            (*tss).privilege_stack_table[0] = stack_end;
+        }
+        self.kernel_stack.store(stack_end.as_u64(), Ordering::Relaxed);
+    }
+}
+
+/// Makes `cpu` the per-CPU data of the calling CPU
+///
+/// Must be called once on every CPU, before anything uses `current`.
+pub fn install(cpu: &'static PerCpu) {
+    let addr = VirtAddr::from_ptr(cpu as *const PerCpu);
+    GsBase::write(addr);
+    KernelGsBase::write(addr);
+}
+
+/// Returns the per-CPU data of the calling CPU, if `install` was called
+pub fn try_current() -> Option<&'static PerCpu> {
+    let mut base = GsBase::read();
+    if base.is_null() {
+        base = KernelGsBase::read();
+        if base.is_null() {
+            return None;
+        }
+        GsBase::write(base);
+    }
+    Some(unsafe { &*base.as_ptr::<PerCpu>() })
+}
+
+/// Returns the per-CPU data of the calling CPU
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per-CPU data not installed")
+}
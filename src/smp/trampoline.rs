@@ -0,0 +1,167 @@
+//! Real mode entry point of the application processors
+//!
+//! A startup IPI starts a CPU in real mode at the beginning of a page below
+//! 1 MiB. The trampoline is copied there, and its parameters (page table,
+//! stack, entry point) are patched in before every startup. It switches to
+//! protected mode, enables paging with the kernel's page table and long
+//! mode, and calls the entry point with the argument.
+//!
+//! The code is position independent, addresses are computed from the segment
+//! the CPU was started in. The page must be identity mapped in the kernel's
+//! page table, since paging is enabled while running from it.
+
+use core::arch::global_asm;
+use core::ptr;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+use crate::memory;
+
+global_asm!(
+    ".section .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov %cs, %ax",
+    "    mov %ax, %ds",
+    // esi holds the linear address of the trampoline from here on
+    "    xor %esi, %esi",
+    "    mov %cs, %si",
+    "    shl $4, %esi",
+    "    lgdtl (ap_gdt_pointer - ap_trampoline_start)",
+    "    mov %cr0, %eax",
+    "    or $1, %eax",
+    "    mov %eax, %cr0",
+    "    ljmpl *(ap_protected_target - ap_trampoline_start)",
+    ".code32",
+    "ap_protected:",
+    "    mov $0x10, %ax",
+    "    mov %ax, %ds",
+    "    mov %ax, %es",
+    "    mov %ax, %ss",
+    // physical address extension
+    "    mov %cr4, %eax",
+    "    or $(1 << 5), %eax",
+    "    mov %eax, %cr4",
+    "    mov (ap_cr3 - ap_trampoline_start)(%esi), %eax",
+    "    mov %eax, %cr3",
+    // long mode and no-execute in EFER
+    "    mov $0xc0000080, %ecx",
+    "    rdmsr",
+    "    or $((1 << 8) | (1 << 11)), %eax",
+    "    wrmsr",
+    // paging and write protection
+    "    mov %cr0, %eax",
+    "    or $((1 << 31) | (1 << 16)), %eax",
+    "    mov %eax, %cr0",
+    "    ljmpl *(ap_long_target - ap_trampoline_start)(%esi)",
+    ".code64",
+    "ap_long:",
+    "    xor %eax, %eax",
+    "    mov %ax, %ds",
+    "    mov %ax, %es",
+    "    mov %ax, %ss",
+    "    mov (ap_stack - ap_trampoline_start)(%rsi), %rsp",
+    "    mov (ap_argument - ap_trampoline_start)(%rsi), %rdi",
+    "    mov (ap_entry - ap_trampoline_start)(%rsi), %rax",
+    "    call *%rax",
+    "    ud2",
+    ".balign 8",
+    "ap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00cf9a000000ffff", // 32 bit code
+    "    .quad 0x00cf92000000ffff", // data
+    "    .quad 0x00af9a000000ffff", // 64 bit code
+    "ap_gdt_pointer:",
+    "    .word ap_gdt_pointer - ap_gdt - 1",
+    "    .long 0",
+    "ap_protected_target:",
+    "    .long 0",
+    "    .word 0x08",
+    "ap_long_target:",
+    "    .long 0",
+    "    .word 0x18",
+    ".balign 8",
+    "ap_cr3: .quad 0",
+    "ap_stack: .quad 0",
+    "ap_entry: .quad 0",
+    "ap_argument: .quad 0",
+    "ap_trampoline_end:",
+    ".previous",
+    options(att_syntax),
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_protected: u8;
+    static ap_long: u8;
+    static ap_gdt: u8;
+    static ap_gdt_pointer: u8;
+    static ap_protected_target: u8;
+    static ap_long_target: u8;
+    static ap_cr3: u8;
+    static ap_stack: u8;
+    static ap_entry: u8;
+    static ap_argument: u8;
+}
+
+/// Offset of a trampoline label from its start
+fn offset(label: &u8) -> usize {
+    label as *const u8 as usize - unsafe { &ap_trampoline_start } as *const u8 as usize
+}
+
+/// Entry point called by the trampoline in long mode, with the argument in `rdi`
+pub type Entry = extern "C" fn(u64) -> !;
+
+/// The trampoline copied to a page below 1 MiB
+pub struct Trampoline {
+    frame: PhysFrame,
+    base: *mut u8,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to `frame` and patches in its own address
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// frame is below 1 MiB, unused, and identity mapped.
+    pub unsafe fn install(frame: PhysFrame) -> Trampoline {
+        let start = &ap_trampoline_start as *const u8;
+        let len = offset(&ap_trampoline_end);
+        assert!(len <= 4096, "AP trampoline larger than a page");
+
+        let base: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        ptr::copy_nonoverlapping(start, base, len);
+
+        let linear = frame.start_address().as_u64() as u32;
+        let trampoline = Trampoline { frame, base };
+        trampoline.write(offset(&ap_gdt_pointer) + 2, linear + offset(&ap_gdt) as u32);
+        trampoline.write(offset(&ap_protected_target), linear + offset(&ap_protected) as u32);
+        trampoline.write(offset(&ap_long_target), linear + offset(&ap_long) as u32);
+        trampoline
+    }
+
+    unsafe fn write<T>(&self, offset: usize, value: T) {
+        ptr::write_unaligned(self.base.add(offset) as *mut T, value);
+    }
+
+    /// Sets what the next CPU started through the trampoline runs
+    ///
+    /// `level_4_frame` must be below 4 GiB, it is loaded in 32 bit mode.
+    pub fn prepare(&self, level_4_frame: PhysFrame, stack_top: VirtAddr, entry: Entry, argument: u64) {
+        assert!(level_4_frame.start_address().as_u64() < 1 << 32, "page table above 4 GiB");
+        unsafe {
+            self.write(offset(&ap_cr3), level_4_frame.start_address().as_u64());
+            self.write(offset(&ap_stack), stack_top.as_u64());
+            self.write(offset(&ap_entry), entry as u64);
+            self.write(offset(&ap_argument), argument);
+        }
+    }
+
+    /// Page number to pass in the startup IPI
+    pub fn page(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+}
@@ -0,0 +1,208 @@
+//! Bounded multi-producer, multi-consumer channel where every receiver sees every value
+//!
+//! Sending never waits: once the buffer is full the oldest value is dropped,
+//! and receivers that had not seen it yet get `RecvError::Lagged`.
+
+use super::{IrqLock, Waiter, WaitQueue};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::task::{Context, Poll};
+
+/// There are no receivers, the value is handed back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// All senders are gone and every value was received
+    Closed,
+    /// The receiver fell behind and this many values were dropped for it
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+struct State<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    /// Sequence number of `buffer[0]`
+    head: u64,
+    /// Sequence number the next sent value gets
+    tail: u64,
+    senders: usize,
+    receivers: usize,
+    waiters: WaitQueue,
+}
+
+struct Shared<T> {
+    state: IrqLock<State<T>>,
+}
+
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+    let shared = Arc::new(Shared {
+        state: IrqLock::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            head: 0,
+            tail: 0,
+            senders: 1,
+            receivers: 1,
+            waiters: WaitQueue::new(),
+        }),
+    });
+    let receiver = Receiver {
+        shared: shared.clone(),
+        next: 0,
+        waiter: None,
+    };
+    (Sender { shared }, receiver)
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends a value to all receivers, returning how many there are
+    ///
+    /// Never waits, but drops the oldest value when the buffer is full. From
+    /// an interrupt handler `T` must not own heap memory, freeing it would
+    /// take the allocator lock the interrupted code may hold.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        self.shared.state.with(|state| {
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if state.buffer.len() == state.capacity {
+                state.buffer.pop_front();
+                state.head += 1;
+            }
+            state.buffer.push_back(value);
+            state.tail += 1;
+            state.waiters.notify_all();
+            Ok(state.receivers)
+        })
+    }
+
+    /// Creates a receiver that gets all values sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = self.shared.state.with(|state| {
+            state.receivers += 1;
+            state.tail
+        });
+        Receiver {
+            shared: self.shared.clone(),
+            next,
+            waiter: None,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.state.with(|state| state.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.with(|state| state.senders += 1);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.with(|state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.waiters.notify_all();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// Sequence number of the next value to receive
+    next: u64,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let next = &mut self.next;
+        self.shared.state.with(|state| {
+            if *next < state.head {
+                let missed = state.head - *next;
+                *next = state.head;
+                return Err(TryRecvError::Lagged(missed));
+            }
+            if *next < state.tail {
+                let value = state.buffer[(*next - state.head) as usize].clone();
+                *next += 1;
+                return Ok(value);
+            }
+            if state.senders == 0 {
+                Err(TryRecvError::Closed)
+            } else {
+                Err(TryRecvError::Empty)
+            }
+        })
+    }
+
+    /// Receives the next value
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        futures_util::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        if let Some(waiter) = &self.waiter {
+            if !waiter.is_notified() {
+                waiter.register(cx.waker());
+                return Poll::Pending;
+            }
+            self.waiter = None;
+        }
+
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Lagged(missed)) => return Poll::Ready(Err(RecvError::Lagged(missed))),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // nothing to receive, wait for the next send
+        let next = self.next;
+        let waiter = Waiter::new(cx.waker());
+        let ready = self.shared.state.with(|state| {
+            // a value may have been sent since `try_recv`
+            if next < state.tail || state.senders == 0 {
+                return true;
+            }
+            state.waiters.push(waiter.clone());
+            false
+        });
+        if ready {
+            cx.waker().wake_by_ref();
+        } else {
+            self.waiter = Some(waiter);
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let waiter = self.waiter.take();
+        self.shared.state.with(|state| {
+            state.receivers -= 1;
+            if let Some(waiter) = &waiter {
+                state.waiters.remove(waiter);
+            }
+        });
+    }
+}
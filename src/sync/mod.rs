@@ -0,0 +1,187 @@
+//! Async synchronization primitives for tasks running on the executor
+//!
+//! Unlike `spin::Mutex`, waiting on these suspends only the waiting task
+//! instead of spinning the whole executor. Their internal state is guarded
+//! with interrupts disabled, so the non-blocking `try_*` operations are also
+//! safe to use from interrupt handlers.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicU8, Ordering};
+use futures_util::task::AtomicWaker;
+use core::task::Waker;
+
+pub mod semaphore;
+pub mod mutex;
+pub mod rwlock;
+pub mod notify;
+pub mod oneshot;
+pub mod mpsc;
+pub mod broadcast;
+
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use notify::Notify;
+
+/// Spin lock that keeps interrupts disabled while held
+///
+/// Interrupt handlers can share state guarded by it with tasks
+/// without deadlocking on a lock the interrupted task holds.
+pub(crate) struct IrqLock<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqLock {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        x86_64::instructions::interrupts::without_interrupts(|| f(&mut self.inner.lock()))
+    }
+}
+
+const WAITING: u8 = 0;
+const NOTIFIED: u8 = 1;
+/// Notified by a broadcast like `Notify::notify_waiters` or a close,
+/// which must not be passed on when the waiting future is dropped
+const NOTIFIED_ALL: u8 = 2;
+
+/// A task waiting in a `WaitQueue`
+pub(crate) struct Waiter {
+    waker: AtomicWaker,
+    state: AtomicU8,
+}
+
+impl Waiter {
+    pub fn new(waker: &Waker) -> Arc<Waiter> {
+        let waiter = Arc::new(Waiter {
+            waker: AtomicWaker::new(),
+            state: AtomicU8::new(WAITING),
+        });
+        waiter.waker.register(waker);
+        waiter
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        self.waker.register(waker);
+    }
+
+    pub fn is_notified(&self) -> bool {
+        self.state.load(Ordering::Acquire) != WAITING
+    }
+
+    /// Returns true if the waiter was woken by a single-waiter notification,
+    /// which has to be handed on if the waiter gives up
+    pub fn is_notified_one(&self) -> bool {
+        self.state.load(Ordering::Acquire) == NOTIFIED
+    }
+
+    /// Wakes the waiting task with a notification meant for it alone
+    pub fn notify(&self) {
+        self.state.store(NOTIFIED, Ordering::Release);
+        self.waker.wake();
+    }
+
+    /// Wakes the waiting task with a notification shared with all waiters
+    pub fn notify_broadcast(&self) {
+        self.state.store(NOTIFIED_ALL, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// FIFO list of waiting tasks
+pub(crate) struct WaitQueue {
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue {
+            waiters: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, waiter: Arc<Waiter>) {
+        self.waiters.push_back(waiter);
+    }
+
+    pub fn front(&self) -> Option<&Arc<Waiter>> {
+        self.waiters.front()
+    }
+
+    /// Removes a waiter that gave up waiting
+    pub fn remove(&mut self, waiter: &Arc<Waiter>) {
+        self.waiters.retain(|w| !Arc::ptr_eq(w, waiter));
+    }
+
+    /// Wakes the longest waiting task, returns false if there was none
+    pub fn notify_one(&mut self) -> bool {
+        match self.waiters.pop_front() {
+            Some(waiter) => {
+                waiter.notify();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn notify_all(&mut self) {
+        while let Some(waiter) = self.waiters.pop_front() {
+            waiter.notify_broadcast();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+#[test_case]
+fn test_mutex_between_tasks() {
+    use crate::task::{Task, simple_executor::SimpleExecutor};
+
+    let counter = Arc::new(Mutex::new(0));
+    let mut executor = SimpleExecutor::new();
+    for _ in 0..3 {
+        let counter = counter.clone();
+        executor.spawn(Task::new(async move {
+            let mut guard = counter.lock().await;
+            let value = *guard;
+            crate::task::yield_now().await;
+            *guard = value + 1;
+        }));
+    }
+    executor.run();
+    assert_eq!(counter.try_lock().map(|guard| *guard), Some(3));
+}
+
+#[test_case]
+fn test_mpsc_backpressure() {
+    use crate::task::{Task, simple_executor::SimpleExecutor};
+
+    let (sender, mut receiver) = mpsc::channel(2);
+    assert!(sender.try_send(1).is_ok());
+    assert!(sender.try_send(2).is_ok());
+    assert_eq!(sender.try_send(3), Err(mpsc::TrySendError::Full(3)));
+
+    let received = Arc::new(Mutex::new(alloc::vec::Vec::new()));
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        for value in 3..6 {
+            sender.send(value).await.expect("receiver dropped");
+        }
+    }));
+    executor.spawn(Task::new({
+        let received = received.clone();
+        async move {
+            while let Some(value) = receiver.recv().await {
+                received.lock().await.push(value);
+            }
+        }
+    }));
+    executor.run();
+    assert_eq!(received.try_lock().map(|values| values.clone()), Some(alloc::vec![1, 2, 3, 4, 5]));
+}
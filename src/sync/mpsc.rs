@@ -0,0 +1,150 @@
+//! Bounded multi-producer, single-consumer channel
+//!
+//! Senders wait while the channel is full, so a slow consumer applies
+//! backpressure instead of the queue growing without limit.
+
+use super::{IrqLock, semaphore::{Acquire, Semaphore, TryAcquireError}};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{pin::Pin, task::{Context, Poll}};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+/// The `Receiver` is gone, the value is handed back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+struct Chan<T> {
+    queue: IrqLock<VecDeque<T>>,
+    /// Free slots in `queue`
+    slots: Semaphore,
+    senders: AtomicUsize,
+    rx_waker: AtomicWaker,
+}
+
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+    let chan = Arc::new(Chan {
+        queue: IrqLock::new(VecDeque::with_capacity(capacity)),
+        slots: Semaphore::new(capacity),
+        senders: AtomicUsize::new(1),
+        rx_waker: AtomicWaker::new(),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends a value, waiting for a free slot if the channel is full
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        if Acquire::new(&self.chan.slots, 1).await.is_err() {
+            return Err(SendError(value));
+        }
+        self.push(value);
+        Ok(())
+    }
+
+    /// Sends a value if there is a free slot
+    ///
+    /// Can be called from interrupt handlers, the buffer is allocated for
+    /// `capacity` values up front and a full channel hands the value back.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.chan.slots.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+        }
+        self.push(value);
+        Ok(())
+    }
+
+    /// Pushes a value into the slot taken from `slots`
+    fn push(&self, value: T) {
+        self.chan.queue.with(|queue| queue.push_back(value));
+        self.chan.rx_waker.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.slots.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.chan.rx_waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value, or `None` once all senders are gone
+    pub async fn recv(&mut self) -> Option<T> {
+        futures_util::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.chan.queue.with(|queue| queue.pop_front());
+        if value.is_some() {
+            self.chan.slots.add_permits(1);
+        }
+        value
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        // fast path
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.chan.rx_waker.register(&cx.waker());
+        if let Some(value) = self.try_recv() {
+            self.chan.rx_waker.take();
+            return Poll::Ready(Some(value));
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            self.chan.rx_waker.take();
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+
+    /// Stops accepting values, senders fail from now on
+    pub fn close(&mut self) {
+        self.chan.slots.close();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,104 @@
+use super::{IrqLock, Waiter, WaitQueue};
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+
+struct State {
+    /// Set by `notify_one` when no task was waiting, consumed by the next `notified`
+    permit: bool,
+    waiters: WaitQueue,
+}
+
+/// Wakes tasks waiting for an event
+///
+/// `notify_one` called while no task waits is remembered, so the next
+/// `notified().await` completes immediately and the event is not lost.
+pub struct Notify {
+    state: IrqLock<State>,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            state: IrqLock::new(State {
+                permit: false,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Waits for a notification
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+
+    /// Wakes the longest waiting task, or stores a permit for the next one
+    ///
+    /// Can be called from interrupt handlers, the waiter list is behind an
+    /// `IrqLock` and each `Notified` keeps its own entry alive, so popping
+    /// one frees nothing.
+    pub fn notify_one(&self) {
+        self.state.with(|state| {
+            if !state.waiters.notify_one() {
+                state.permit = true;
+            }
+        });
+    }
+
+    /// Wakes every task currently waiting, without storing a permit
+    pub fn notify_waiters(&self) {
+        self.state.with(|state| state.waiters.notify_all());
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        if let Some(waiter) = &this.waiter {
+            waiter.register(cx.waker());
+            if !waiter.is_notified() {
+                return Poll::Pending;
+            }
+            this.waiter = None;
+            return Poll::Ready(());
+        }
+
+        let notify = this.notify;
+        notify.state.with(|state| {
+            if state.permit {
+                state.permit = false;
+                Poll::Ready(())
+            } else {
+                let waiter = Waiter::new(cx.waker());
+                state.waiters.push(waiter.clone());
+                this.waiter = Some(waiter);
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            let notified_one = self.notify.state.with(|state| {
+                state.waiters.remove(&waiter);
+                waiter.is_notified_one()
+            });
+            // the notification was meant for a single waiter, pass it on
+            if notified_one {
+                self.notify.notify_one();
+            }
+        }
+    }
+}
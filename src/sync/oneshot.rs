@@ -0,0 +1,122 @@
+//! Channel for sending a single value between tasks
+
+use super::IrqLock;
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+/// The `Sender` was dropped without sending a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_done: bool,
+    receiver_dropped: bool,
+}
+
+struct Inner<T> {
+    state: IrqLock<State<T>>,
+    waker: AtomicWaker,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: IrqLock::new(State {
+            value: None,
+            sender_done: false,
+            receiver_dropped: false,
+        }),
+        waker: AtomicWaker::new(),
+    });
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the value, handing it back if the `Receiver` is gone
+    ///
+    /// Not for interrupt handlers: dropping the sender may free the channel,
+    /// and the handed back value, while the interrupted code holds the heap.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let result = self.inner.state.with(|state| {
+            if state.receiver_dropped {
+                return Err(value);
+            }
+            state.value = Some(value);
+            state.sender_done = true;
+            Ok(())
+        });
+        if result.is_ok() {
+            self.inner.waker.wake();
+        }
+        result
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.with(|state| state.receiver_dropped)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.state.with(|state| state.sender_done = true);
+        self.inner.waker.wake();
+    }
+}
+
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.inner.state.with(|state| match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_done => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        // fast path
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        self.inner.waker.register(&cx.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.inner.waker.take();
+                Poll::Ready(Ok(value))
+            }
+            Err(TryRecvError::Closed) => {
+                self.inner.waker.take();
+                Poll::Ready(Err(RecvError))
+            }
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.state.with(|state| state.receiver_dropped = true);
+    }
+}
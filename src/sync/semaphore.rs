@@ -0,0 +1,195 @@
+use super::{IrqLock, Waiter};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+
+/// Error returned when acquiring permits from a closed `Semaphore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+/// Error returned by `Semaphore::try_acquire`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+struct State {
+    permits: usize,
+    closed: bool,
+    /// Waiting tasks with the number of permits they need, served in order
+    waiters: VecDeque<(Arc<Waiter>, usize)>,
+}
+
+/// Counting semaphore handing out permits in FIFO order
+///
+/// A large request at the front of the queue holds back smaller ones
+/// behind it, so no waiter starves.
+pub struct Semaphore {
+    state: IrqLock<State>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: IrqLock::new(State {
+                permits,
+                closed: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.with(|state| state.permits)
+    }
+
+    /// Waits for a single permit, which is returned when the guard drops
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(1).await
+    }
+
+    pub async fn acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, AcquireError> {
+        Acquire::new(self, permits).await?;
+        Ok(SemaphorePermit { semaphore: self, permits })
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes permits without waiting, also usable from interrupt handlers
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_take(permits)?;
+        Ok(SemaphorePermit { semaphore: self, permits })
+    }
+
+    fn try_take(&self, permits: usize) -> Result<(), TryAcquireError> {
+        self.state.with(|state| {
+            if state.closed {
+                Err(TryAcquireError::Closed)
+            } else if state.waiters.is_empty() && state.permits >= permits {
+                state.permits -= permits;
+                Ok(())
+            } else {
+                Err(TryAcquireError::NoPermits)
+            }
+        })
+    }
+
+    /// Returns permits to the semaphore, waking waiters that can now proceed
+    pub fn add_permits(&self, permits: usize) {
+        self.state.with(|state| {
+            state.permits += permits;
+            while let Some((_, needed)) = state.waiters.front() {
+                if *needed > state.permits {
+                    break;
+                }
+                state.permits -= *needed;
+                let (waiter, _) = state.waiters.pop_front().unwrap();
+                waiter.notify();
+            }
+        });
+    }
+
+    /// Fails all current and future acquires
+    pub fn close(&self) {
+        self.state.with(|state| {
+            state.closed = true;
+            while let Some((waiter, _)) = state.waiters.pop_front() {
+                waiter.notify_broadcast();
+            }
+        });
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.with(|state| state.closed)
+    }
+}
+
+/// Future taking permits from a `Semaphore`, without a guard
+pub(crate) struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<'a> Acquire<'a> {
+    pub(crate) fn new(semaphore: &'a Semaphore, permits: usize) -> Self {
+        Acquire {
+            semaphore,
+            permits,
+            waiter: None,
+        }
+    }
+}
+
+impl Future for Acquire<'_> {
+    type Output = Result<(), AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), AcquireError>> {
+        let this = &mut *self;
+        if let Some(waiter) = &this.waiter {
+            waiter.register(cx.waker());
+            if !waiter.is_notified() {
+                return Poll::Pending;
+            }
+            // permits are only handed out with `notify`, a broadcast means closed
+            let granted = waiter.is_notified_one();
+            this.waiter = None;
+            return if granted { Poll::Ready(Ok(())) } else { Poll::Ready(Err(AcquireError)) };
+        }
+
+        let semaphore = this.semaphore;
+        let permits = this.permits;
+        semaphore.state.with(|state| {
+            if state.closed {
+                Poll::Ready(Err(AcquireError))
+            } else if state.waiters.is_empty() && state.permits >= permits {
+                state.permits -= permits;
+                Poll::Ready(Ok(()))
+            } else {
+                let waiter = Waiter::new(cx.waker());
+                state.waiters.push_back((waiter.clone(), permits));
+                this.waiter = Some(waiter);
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            let granted = self.semaphore.state.with(|state| {
+                state.waiters.retain(|(w, _)| !Arc::ptr_eq(w, &waiter));
+                waiter.is_notified_one()
+            });
+            // permits were handed to us after all, give them back
+            if granted {
+                self.semaphore.add_permits(self.permits);
+            }
+        }
+    }
+}
+
+/// Permits taken from a `Semaphore`, returned when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits taken instead of returning them on drop
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::smp::percpu;
+
+/// Default time a task may spend in a single poll, in TSC cycles
+///
+/// Roughly 10ms on the CPUs QEMU usually emulates.
+pub const DEFAULT_POLL_BUDGET: u64 = 20_000_000;
+
+static POLL_BUDGET: AtomicU64 = AtomicU64::new(DEFAULT_POLL_BUDGET);
+
+pub fn poll_budget() -> u64 {
+    POLL_BUDGET.load(Ordering::Relaxed)
+}
+
+/// Sets the time a task may spend in a single poll before
+/// `should_yield` returns true and the executor warns about it
+pub fn set_poll_budget(cycles: u64) {
+    POLL_BUDGET.store(cycles, Ordering::Relaxed);
+}
+
+/// Called by the executor right before polling a task
+pub(crate) fn start_poll() {
+    percpu::current().set_poll_start(now());
+}
+
+/// Cycles elapsed since the executor started polling the current task
+pub(crate) fn elapsed() -> u64 {
+    now().saturating_sub(percpu::current().poll_start())
+}
+
+fn now() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns true once the current task has used up its poll budget
+///
+/// Long running loops should check this and `yield_now().await`
+/// to let other tasks run.
+pub fn should_yield() -> bool {
+    elapsed() >= poll_budget()
+}
+
+/// Yields execution back to the executor once, rescheduling the current task
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
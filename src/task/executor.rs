@@ -1,53 +1,138 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use super::{Task, TaskId, TaskInfo, TaskState, Priority, JoinHandle, info, run_queue::RunQueue};
+use alloc::{borrow::Cow, collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::{Waker, Context, Poll};
-use crossbeam_queue::ArrayQueue;
+use crossbeam_queue::SegQueue;
 
+/// Tasks that the executor of any CPU may pick up, see `spawn_anywhere`
+static SHARED_QUEUE: SegQueue<SendTask> = SegQueue::new();
+
+/// A task whose future is `Send`, so it may start on another CPU
+pub struct SendTask(Task);
+
+// only constructed from `Send` futures, the rest of `Task` is thread safe
+unsafe impl Send for SendTask {}
+
+impl SendTask {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> SendTask {
+        SendTask(Task::new(future))
+    }
+
+    pub fn with_name(self, name: impl Into<Cow<'static, str>>) -> SendTask {
+        SendTask(self.0.with_name(name))
+    }
+
+    pub fn with_priority(self, priority: Priority) -> SendTask {
+        SendTask(self.0.with_priority(priority))
+    }
+}
+
+/// Queues a task for whichever CPU's executor is idle first
+///
+/// Once picked up, the task stays on that CPU.
+pub fn spawn_anywhere(task: SendTask) {
+    SHARED_QUEUE.push(task);
+    crate::smp::kick_all();
+}
+
+/// Runs tasks on one CPU, there is one executor with its own run queue per CPU
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    task_queue: Arc<RunQueue>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    spawn_queue: Arc<SegQueue<Task>>,
+    /// CPU the executor runs on, woken when its tasks are woken elsewhere
+    cpu: usize,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(RunQueue::new()),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(SegQueue::new()),
+            cpu: crate::smp::current_id(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let priority = task.priority;
+        info::register(task_id, task.name(), priority, task.stats.clone());
+        let waker = TaskWaker::new(task_id, priority, task.stats.clone(), self.task_queue.clone(), self.cpu);
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        let same_priority = self.tasks.values().filter(|task| task.priority == priority).count();
+        self.task_queue.reserve(priority, same_priority);
+        waker.wake_task();
+        self.waker_cache.insert(task_id, waker);
+    }
+
+    /// Returns the metadata of all tasks owned by this executor
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        info::get(self.tasks.keys())
+    }
+
+    /// Returns a handle that can spawn tasks onto this executor,
+    /// also from inside running tasks
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+        }
+    }
+
+    /// Moves tasks submitted through a `Spawner` into the executor,
+    /// and takes one task from the shared queue
+    fn spawn_pending(&mut self) {
+        while let Some(task) = self.spawn_queue.pop() {
+            self.spawn(task);
+        }
+        // one at a time, so the other idle CPUs get some too
+        if let Some(SendTask(task)) = SHARED_QUEUE.pop() {
+            self.spawn(task);
+        }
     }
 
     fn run_ready_tasks(&mut self) {
+        self.spawn_pending();
+
         // destructive `self` to avoid borrow checker errors
         let Self {
             tasks,
             task_queue,
-            waker_cache
+            waker_cache,
+            spawn_queue: _,
+            cpu: _,
         } = self;
 
+        let cpu = crate::smp::percpu::current();
         while let Some(task_id) = task_queue.pop() {
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue, // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            let task_waker = match waker_cache.get(&task_id) {
+                Some(task_waker) => task_waker,
+                None => continue,
+            };
+            // cleared before polling, so wakes during the poll queue the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            cpu.set_current_task(Some(task_id));
+            let poll = task.poll(&mut context);
+            cpu.set_current_task(None);
+            match poll {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
+                    // leftover wakers must not queue the finished task anymore
+                    task_waker.queued.store(true, Ordering::Release);
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    info::unregister(task_id);
                 },
                 Poll::Pending => { }
             }
@@ -61,16 +146,26 @@ impl Executor {
         }
     }
 
+    fn is_idle(&self) -> bool {
+        self.task_queue.is_empty() && self.spawn_queue.is_empty() && SHARED_QUEUE.is_empty()
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         // fast path
-        if !self.task_queue.is_empty() {
+        if !self.is_idle() {
+            return;
+        }
+
+        // let other kernel threads run instead of halting until the next tick
+        if crate::thread::has_ready_threads() {
+            crate::thread::yield_now();
             return;
         }
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.is_idle() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -78,21 +173,69 @@ impl Executor {
     }
 }
 
+/// Cloneable handle for spawning tasks onto an `Executor`
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<SegQueue<Task>>,
+}
+
+impl Spawner {
+    /// Spawns a future, returning a handle to await its output or abort it
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task);
+        handle
+    }
+
+    /// Like `spawn`, but names the task for task listings
+    pub fn spawn_named<F>(&self, name: impl Into<Cow<'static, str>>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task.with_name(name));
+        handle
+    }
+
+    pub fn spawn_task(&self, task: Task) {
+        self.spawn_queue.push(task);
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    stats: Arc<info::TaskStats>,
+    task_queue: Arc<RunQueue>,
+    /// CPU whose executor owns `task_queue`
+    cpu: usize,
+    /// Set while the task sits in `task_queue`, so repeated wakes queue it only once
+    queued: AtomicBool,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, priority: Priority, stats: Arc<info::TaskStats>, task_queue: Arc<RunQueue>, cpu: usize) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
+            priority,
+            stats,
             task_queue,
-        }))
+            cpu,
+            queued: AtomicBool::new(false),
+        })
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.stats.set_state(TaskState::Ready);
+        self.task_queue.push(self.task_id, self.priority);
+        // the owning executor may be halted on another CPU
+        crate::smp::kick(self.cpu);
     }
 }
 
@@ -0,0 +1,146 @@
+use super::{TaskId, Priority};
+use alloc::{collections::{BTreeMap, VecDeque}, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crossbeam_utils::atomic::AtomicCell;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Number of finished tasks kept around for introspection
+const FINISHED_HISTORY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Woken and waiting in the run queue
+    Ready,
+    /// Waiting for a waker to be called
+    Pending,
+    Finished,
+}
+
+/// Runtime statistics of a task, updated by the executor on every poll
+#[derive(Debug)]
+pub(crate) struct TaskStats {
+    spawned_at: usize,
+    poll_count: AtomicU64,
+    poll_cycles: AtomicU64,
+    budget_overruns: AtomicU64,
+    state: AtomicCell<TaskState>,
+}
+
+impl TaskStats {
+    pub(crate) fn new() -> Self {
+        TaskStats {
+            spawned_at: crate::time::get(),
+            poll_count: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            budget_overruns: AtomicU64::new(0),
+            state: AtomicCell::new(TaskState::Ready),
+        }
+    }
+
+    pub(crate) fn set_state(&self, state: TaskState) {
+        self.state.store(state);
+    }
+
+    pub(crate) fn record_poll(&self, cycles: u64) {
+        self.poll_count.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles.fetch_add(cycles, Ordering::Relaxed);
+    }
+
+    /// Counts a poll that exceeded the poll budget, returning the new total
+    pub(crate) fn record_overrun(&self) -> u64 {
+        self.budget_overruns.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// Snapshot of a task's metadata
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub priority: Priority,
+    /// Timer tick the task was created at
+    pub spawned_at: usize,
+    pub poll_count: u64,
+    /// Cumulative time spent in `poll`, in TSC cycles
+    pub poll_cycles: u64,
+    /// Number of polls that took longer than the poll budget
+    pub budget_overruns: u64,
+    pub state: TaskState,
+}
+
+struct Entry {
+    name: String,
+    priority: Priority,
+    stats: Arc<TaskStats>,
+}
+
+impl Entry {
+    fn info(&self, id: TaskId) -> TaskInfo {
+        TaskInfo {
+            id,
+            name: self.name.clone(),
+            priority: self.priority,
+            spawned_at: self.stats.spawned_at,
+            poll_count: self.stats.poll_count.load(Ordering::Relaxed),
+            poll_cycles: self.stats.poll_cycles.load(Ordering::Relaxed),
+            budget_overruns: self.stats.budget_overruns.load(Ordering::Relaxed),
+            state: self.stats.state.load(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    live: BTreeMap<TaskId, Entry>,
+    finished: VecDeque<TaskInfo>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+pub(crate) fn register(id: TaskId, name: &str, priority: Priority, stats: Arc<TaskStats>) {
+    REGISTRY.lock().live.insert(id, Entry {
+        name: String::from(name),
+        priority,
+        stats,
+    });
+}
+
+pub(crate) fn unregister(id: TaskId) {
+    let mut registry = REGISTRY.lock();
+    if let Some(entry) = registry.live.remove(&id) {
+        if registry.finished.len() >= FINISHED_HISTORY {
+            registry.finished.pop_front();
+        }
+        let info = entry.info(id);
+        registry.finished.push_back(info);
+    }
+}
+
+/// Returns the metadata of the given tasks that are still registered
+pub(crate) fn get<'a>(ids: impl Iterator<Item = &'a TaskId>) -> Vec<TaskInfo> {
+    let registry = REGISTRY.lock();
+    ids.filter_map(|id| registry.live.get(id).map(|entry| entry.info(*id)))
+        .collect()
+}
+
+/// Returns the metadata of every live task, followed by recently finished ones
+pub fn list() -> Vec<TaskInfo> {
+    collect(&REGISTRY.lock())
+}
+
+/// Like `list`, but `None` instead of waiting if the registry is locked,
+/// for the monitor which may have stopped the kernel while it was
+pub fn try_list() -> Option<Vec<TaskInfo>> {
+    REGISTRY.try_lock().map(|registry| collect(&registry))
+}
+
+fn collect(registry: &Registry) -> Vec<TaskInfo> {
+    let mut tasks: Vec<TaskInfo> = registry.live.iter()
+        .map(|(id, entry)| entry.info(*id))
+        .collect();
+    tasks.extend(registry.finished.iter().cloned());
+    tasks
+}
@@ -0,0 +1,115 @@
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{pin::Pin, task::{Poll, Context}};
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+/// Queue handing events from an interrupt handler to an async task
+///
+/// Can be declared as a `static`; the backing buffer is allocated when the
+/// first stream is created, events pushed before that are counted as dropped.
+pub struct IrqQueue<T> {
+    name: &'static str,
+    capacity: usize,
+    queue: OnceCell<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    dropped: AtomicU64,
+}
+
+impl<T> IrqQueue<T> {
+    pub const fn new(name: &'static str, capacity: usize) -> Self {
+        IrqQueue {
+            name,
+            capacity,
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Called by interrupt handlers
+    ///
+    /// Must not block or allocate
+    pub fn push(&self, value: T) {
+        match self.queue.try_get() {
+            Ok(queue) if queue.push(value).is_ok() => self.waker.wake(),
+            _ => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns a stream of the queued events, allocating the queue if needed
+    ///
+    /// Events go to whichever stream polls first, so there should only be
+    /// one consumer at a time.
+    pub fn stream(&self) -> IrqStream<'_, T> {
+        let capacity = self.capacity;
+        self.queue.try_init_once(|| ArrayQueue::new(capacity)).ok();
+        IrqStream {
+            queue: self,
+            reported_dropped: self.dropped(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of events lost because the queue was full or not yet initialized
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Takes a queued event without waiting, for consumers that can't
+    /// poll a stream, like the debugger while interrupts are disabled
+    pub fn try_pop(&self) -> Option<T> {
+        self.queue.try_get().ok().and_then(|queue| queue.pop())
+    }
+}
+
+pub struct IrqStream<'a, T> {
+    queue: &'a IrqQueue<T>,
+    /// Value of the drop counter at the last warning
+    reported_dropped: u64,
+}
+
+impl<T> IrqStream<'_, T> {
+    /// Logs events dropped since the last check, which the
+    /// interrupt handler itself must not do
+    fn report_dropped(&mut self) {
+        let dropped = self.queue.dropped();
+        if dropped != self.reported_dropped {
+            log::warn!("{} queue full; dropped {} events", self.queue.name, dropped - self.reported_dropped);
+            self.reported_dropped = dropped;
+        }
+    }
+}
+
+impl<T> Stream for IrqStream<'_, T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        this.report_dropped();
+
+        // fast path
+        if let Some(value) = this.queue.try_pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        this.queue.waker.register(&cx.waker());
+        match this.queue.try_pop() {
+            Some(value) => {
+                this.queue.waker.take();
+                Poll::Ready(Some(value))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Error returned by a `JoinHandle` whose task did not run to completion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    Aborted,
+}
+
+/// State shared between a spawned task and its `JoinHandle`
+struct JoinInner<T> {
+    output: Mutex<Option<T>>,
+    finished: AtomicBool,
+    aborted: AtomicBool,
+    /// Waker of the task awaiting the `JoinHandle`
+    join_waker: AtomicWaker,
+    /// Waker of the spawned task itself, used to reschedule it on abort
+    task_waker: AtomicWaker,
+}
+
+/// Wraps a spawned future, storing its output for the `JoinHandle`
+/// and completing early once the task is aborted
+pub(crate) struct Joinable<F: Future> {
+    future: F,
+    inner: Arc<JoinInner<F::Output>>,
+}
+
+pub(crate) fn joinable<F: Future>(future: F) -> (Joinable<F>, JoinHandle<F::Output>) {
+    let inner = Arc::new(JoinInner {
+        output: Mutex::new(None),
+        finished: AtomicBool::new(false),
+        aborted: AtomicBool::new(false),
+        join_waker: AtomicWaker::new(),
+        task_waker: AtomicWaker::new(),
+    });
+    let handle = JoinHandle { inner: inner.clone() };
+    (Joinable { future, inner }, handle)
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // the inner future is never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.inner.aborted.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        this.inner.task_waker.register(&cx.waker());
+
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                *this.inner.output.lock() = Some(output);
+                this.inner.finished.store(true, Ordering::Release);
+                this.inner.join_waker.wake();
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Awaits the output of a spawned task
+///
+/// Dropping the handle detaches the task, it keeps running in the background.
+pub struct JoinHandle<T> {
+    inner: Arc<JoinInner<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Cancels the task; it is dropped the next time the executor picks it up
+    ///
+    /// Has no effect if the task already finished.
+    pub fn abort(&self) {
+        if self.inner.finished.load(Ordering::Acquire) {
+            return;
+        }
+        self.inner.aborted.store(true, Ordering::Release);
+        self.inner.task_waker.wake();
+        self.inner.join_waker.wake();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.inner.finished.load(Ordering::Acquire) || self.inner.aborted.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        let inner = &self.inner;
+
+        // fast path
+        if let Some(output) = inner.output.lock().take() {
+            return Poll::Ready(Ok(output));
+        }
+
+        inner.join_waker.register(&cx.waker());
+        if let Some(output) = inner.output.lock().take() {
+            inner.join_waker.take();
+            return Poll::Ready(Ok(output));
+        }
+        if inner.aborted.load(Ordering::Acquire) {
+            inner.join_waker.take();
+            return Poll::Ready(Err(JoinError::Aborted));
+        }
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_join_handle_output() {
+    use super::{Task, simple_executor::SimpleExecutor};
+
+    let joined = Arc::new(AtomicBool::new(false));
+    let (task, handle) = Task::joinable(async { 42 });
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(task);
+    executor.spawn(Task::new({
+        let joined = joined.clone();
+        async move {
+            assert_eq!(handle.await, Ok(42));
+            joined.store(true, Ordering::Relaxed);
+        }
+    }));
+    executor.run();
+    assert!(joined.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn test_join_handle_abort() {
+    use super::{Task, simple_executor::SimpleExecutor};
+
+    let joined = Arc::new(AtomicBool::new(false));
+    let (task, handle) = Task::joinable(futures_util::future::pending::<()>());
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(task);
+    executor.spawn(Task::new({
+        let joined = joined.clone();
+        async move {
+            handle.abort();
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+            joined.store(true, Ordering::Relaxed);
+        }
+    }));
+    executor.run();
+    assert!(joined.load(Ordering::Relaxed));
+}
@@ -1,65 +1,25 @@
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use core::{pin::Pin, task::{Poll, Context}};
-use futures_util::stream::{Stream, StreamExt};
-use futures_util::task::AtomicWaker;
+use futures_util::stream::StreamExt;
 use crate::peripheral::{ISubject, keyboard::Keyboard};
+use super::irq_queue::{IrqQueue, IrqStream};
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODE_QUEUE: IrqQueue<u8> = IrqQueue::new("keyboard scancode", 100);
 
 /// Called by the keyboard interrupt handler
 /// 
 /// Must not block or allocate
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            log::warn!("scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
-        }
-    }
-}
-
-pub struct ScancodeStream {
-    _private: (),
+    SCANCODE_QUEUE.push(scancode);
 }
 
-impl ScancodeStream {
-    pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should be called once");
-        ScancodeStream { _private: () }
-    }
-}
-
-impl Stream for ScancodeStream {
-    type Item = u8;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("scancode queue should be initialized by now");
-
-        // fast path
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            None => Poll::Pending,
-        }
-    }
+pub fn scancode_stream() -> IrqStream<'static, u8> {
+    SCANCODE_QUEUE.stream()
 }
 
 pub async fn process_keypresses(mut keyboard_subject: Keyboard<'_>) {
-    let mut scancodes = ScancodeStream::new();
+    let mut scancodes = scancode_stream();
     log::debug!("keyboard scancode stream initialized");
     while let Some(scancode) = scancodes.next().await {
         keyboard_subject.scancode = Some(scancode);
         keyboard_subject.notify();
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,7 @@
 use core::{future::Future, pin::Pin};
 use core::task::{Context, Poll};
-use alloc::boxed::Box;
+use alloc::{boxed::Box, borrow::Cow, sync::Arc};
+use core::fmt;
 use core::sync::atomic::{AtomicU64, Ordering};
 
 pub mod simple_executor;
@@ -8,20 +9,58 @@ pub mod keyboard;
 pub mod mouse;
 pub mod executor;
 pub mod term;
+pub mod serial;
 pub mod canvasgame;
+pub mod join;
+pub mod info;
+pub mod coop;
+pub mod irq_queue;
+mod run_queue;
+
+pub use join::{JoinHandle, JoinError};
+pub use info::{TaskInfo, TaskState};
+pub use coop::{yield_now, should_yield};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Scheduling priority of a task
+///
+/// Ready tasks of a higher priority are polled first, e.g. input handling
+/// before the terminal, and the terminal before rendering the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High = 0,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
 }
 
 pub struct Task {
     id: TaskId,
+    name: Cow<'static, str>,
+    priority: Priority,
+    stats: Arc<info::TaskStats>,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
@@ -29,11 +68,65 @@ impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            name: Cow::Borrowed("unnamed"),
+            priority: Priority::Normal,
+            stats: Arc::new(info::TaskStats::new()),
             future: Box::pin(future),
         }
     }
 
+    /// Sets the name shown for the task in task listings
+    pub fn with_name(mut self, name: impl Into<Cow<'static, str>>) -> Task {
+        self.name = name.into();
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Task {
+        self.priority = priority;
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Creates a task whose output can be awaited through the returned `JoinHandle`
+    pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        (Task::new(future), handle)
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        // set before polling, so a wake from inside `poll` is not overwritten
+        self.stats.set_state(TaskState::Pending);
+        coop::start_poll();
+        let result = self.future.as_mut().poll(context);
+        let cycles = coop::elapsed();
+        self.stats.record_poll(cycles);
+        if cycles > coop::poll_budget() {
+            // warn on the 1st, 2nd, 4th, 8th... overrun to keep the log readable
+            let overruns = self.stats.record_overrun();
+            if overruns.is_power_of_two() {
+                log::warn!(
+                    "task {} ({}) blocked the executor for {} cycles without yielding ({} times so far)",
+                    self.id, self.name, cycles, overruns
+                );
+            }
+        }
+        if result.is_ready() {
+            self.stats.set_state(TaskState::Finished);
+        }
+        result
     }
 }
\ No newline at end of file
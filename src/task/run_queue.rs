@@ -0,0 +1,121 @@
+use super::{TaskId, Priority};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use spin::RwLock;
+
+/// Initial capacity of each priority level, it grows as more tasks are spawned
+const INITIAL_CAPACITY: usize = 64;
+
+/// Number of times a ready level may be passed over for higher priority
+/// tasks before it is served anyway
+const STARVATION_LIMIT: usize = 16;
+
+/// Queue of tasks that are ready to be polled, with one FIFO per priority level
+///
+/// Every task is in the queue at most once (see `TaskWaker`), so as long as
+/// the capacity of each level is kept at or above the number of tasks with
+/// that priority, pushing never fails. That makes it safe to push from
+/// interrupt handlers, which must not block, allocate or panic.
+pub(crate) struct RunQueue {
+    levels: [Level; Priority::COUNT],
+}
+
+struct Level {
+    queue: RwLock<ArrayQueue<TaskId>>,
+    /// Pops served by a higher level while this one had ready tasks
+    skipped: AtomicUsize,
+}
+
+impl Level {
+    fn new() -> Self {
+        Level {
+            queue: RwLock::new(ArrayQueue::new(INITIAL_CAPACITY)),
+            skipped: AtomicUsize::new(0),
+        }
+    }
+
+    fn pop(&self) -> Option<TaskId> {
+        let task_id = self.queue.read().pop();
+        if task_id.is_some() {
+            self.skipped.store(0, Ordering::Relaxed);
+        }
+        task_id
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.read().is_empty()
+    }
+}
+
+impl RunQueue {
+    pub fn new() -> Self {
+        RunQueue {
+            levels: [Level::new(), Level::new(), Level::new()],
+        }
+    }
+
+    /// Called by wakers, possibly from interrupt context
+    ///
+    /// Must not block or allocate
+    pub fn push(&self, task_id: TaskId, priority: Priority) {
+        if self.levels[priority as usize].queue.read().push(task_id).is_err() {
+            // only reachable if a task was queued twice or `reserve` was skipped
+            unreachable!("run queue capacity below task count");
+        }
+    }
+
+    /// Pops the next task to poll, highest priority first
+    ///
+    /// A level that was passed over `STARVATION_LIMIT` times in a row is
+    /// served first, so lower priority tasks still make progress under load.
+    pub fn pop(&self) -> Option<TaskId> {
+        for level in self.levels.iter().rev() {
+            if level.skipped.load(Ordering::Relaxed) >= STARVATION_LIMIT {
+                if let Some(task_id) = level.pop() {
+                    return Some(task_id);
+                }
+                level.skipped.store(0, Ordering::Relaxed);
+            }
+        }
+
+        let mut task_id = None;
+        for level in self.levels.iter() {
+            if task_id.is_none() {
+                task_id = level.pop();
+            } else if !level.is_empty() {
+                level.skipped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        task_id
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.iter().all(Level::is_empty)
+    }
+
+    /// Grows the given priority level so that it can hold at least `tasks` entries
+    ///
+    /// Must not be called from interrupt context.
+    pub fn reserve(&self, priority: Priority, tasks: usize) {
+        use x86_64::instructions::interrupts;
+
+        let level = &self.levels[priority as usize];
+        let capacity = level.queue.read().capacity();
+        if tasks <= capacity {
+            return;
+        }
+        let new_capacity = tasks.max(capacity * 2);
+        log::debug!("growing {:?} run queue from {} to {} entries", priority, capacity, new_capacity);
+
+        let new_queue = ArrayQueue::new(new_capacity);
+        // wakers running in interrupt handlers take the read lock,
+        // so they must not fire while the write lock is held
+        interrupts::without_interrupts(|| {
+            let mut queue = level.queue.write();
+            while let Some(task_id) = queue.pop() {
+                new_queue.push(task_id).ok();
+            }
+            *queue = new_queue;
+        });
+    }
+}
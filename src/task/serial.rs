@@ -0,0 +1,153 @@
+//! Serial console, and input of the other serial streams
+//!
+//! Bytes received on the port the console stream is routed to (COM1 by
+//! default) are decoded like keyboard input and fed to the terminal, and
+//! everything written to the `Console` virtual terminal is mirrored to that
+//! port, so the kernel can be driven headlessly with `-serial stdio`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::stream::StreamExt;
+use crate::serial::{self, ComPort, SerialStream};
+use crate::vga::term::{EscapeChar, VirtualTerminals};
+use super::irq_queue::{IrqQueue, IrqStream};
+
+static CONSOLE_QUEUE: IrqQueue<u8> = IrqQueue::new("serial console input", 256);
+static DEBUG_QUEUE: IrqQueue<u8> = IrqQueue::new("serial debug input", 1024);
+
+/// Set while `process_input` runs, `Term` only mirrors the console then
+static CONSOLE_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Called by the serial interrupt handlers, hands the byte to the
+/// streams routed to `port`
+///
+/// Must not block or allocate
+pub(crate) fn add_byte(port: ComPort, byte: u8) {
+    if serial::route_of(SerialStream::Console) == Some(port) {
+        CONSOLE_QUEUE.push(byte);
+    }
+    if serial::route_of(SerialStream::Debug) == Some(port) {
+        DEBUG_QUEUE.push(byte);
+    }
+}
+
+/// Bytes received for the console
+pub fn console_stream() -> IrqStream<'static, u8> {
+    CONSOLE_QUEUE.stream()
+}
+
+/// Bytes received for the debugger protocol
+pub fn debug_stream() -> IrqStream<'static, u8> {
+    DEBUG_QUEUE.stream()
+}
+
+/// Takes a byte received for the debugger protocol without waiting
+pub(crate) fn try_debug_byte() -> Option<u8> {
+    DEBUG_QUEUE.try_pop()
+}
+
+pub fn is_console_active() -> bool {
+    CONSOLE_ACTIVE.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    Normal,
+    /// After ESC
+    Escape,
+    /// After ESC [
+    Csi,
+    /// After ESC O
+    Ss3,
+    /// After ESC [ and a digit, waiting for `~`
+    CsiNumber(u8),
+}
+
+/// Turns the bytes a VT100-like terminal sends into terminal input characters
+///
+/// Arrow keys, Home and End scroll like on the keyboard, F1-F4 switch virtual
+/// terminals and carriage returns become newlines.
+pub struct InputDecoder {
+    state: DecoderState,
+}
+
+impl InputDecoder {
+    pub fn new() -> Self {
+        InputDecoder { state: DecoderState::Normal }
+    }
+
+    pub fn decode(&mut self, byte: u8) -> Option<char> {
+        let (state, output) = match (self.state, byte) {
+            (DecoderState::Normal, 0x1b) => (DecoderState::Escape, None),
+            (DecoderState::Normal, b'\r') => (DecoderState::Normal, Some('\n')),
+            (DecoderState::Normal, 0x7f) => (DecoderState::Normal, Some('\u{8}')),
+            (DecoderState::Normal, byte) => (DecoderState::Normal, Some(byte as char)),
+            (DecoderState::Escape, b'[') => (DecoderState::Csi, None),
+            (DecoderState::Escape, b'O') => (DecoderState::Ss3, None),
+            (DecoderState::Csi, byte @ b'0'..=b'9') => (DecoderState::CsiNumber(byte - b'0'), None),
+            (DecoderState::CsiNumber(number), byte @ b'0'..=b'9') => {
+                (DecoderState::CsiNumber(number.saturating_mul(10).saturating_add(byte - b'0')), None)
+            }
+            (DecoderState::Csi, key) | (DecoderState::Ss3, key) => (DecoderState::Normal, Self::key(key)),
+            (DecoderState::CsiNumber(number), b'~') => (DecoderState::Normal, Self::numbered_key(number)),
+            // unknown sequences are dropped
+            _ => (DecoderState::Normal, None),
+        };
+        self.state = state;
+        output
+    }
+
+    fn key(key: u8) -> Option<char> {
+        let escape = match key {
+            b'A' => EscapeChar::ScrollUp,
+            b'B' => EscapeChar::ScrollDown,
+            b'C' => EscapeChar::ScrollRight,
+            b'D' => EscapeChar::ScrollLeft,
+            b'H' => EscapeChar::ScrollHome,
+            b'F' => EscapeChar::ScrollEnd,
+            b'P' => return Some(VirtualTerminals::KernelLog as u8 as char),
+            b'Q' => return Some(VirtualTerminals::Console as u8 as char),
+            b'R' => return Some(VirtualTerminals::GUI as u8 as char),
+            b'S' => return Some(VirtualTerminals::CanvasGame as u8 as char),
+            _ => return None,
+        };
+        Some(escape as u8 as char)
+    }
+
+    /// Keys sent as ESC [ number ~
+    fn numbered_key(number: u8) -> Option<char> {
+        match number {
+            1 | 7 => Some(EscapeChar::ScrollHome as u8 as char),
+            4 | 8 => Some(EscapeChar::ScrollEnd as u8 as char),
+            15 => Some(VirtualTerminals::Tasks as u8 as char),
+            _ => None,
+        }
+    }
+}
+
+/// Feeds the bytes received for the console to the terminal as input
+pub async fn process_input() {
+    let mut bytes = console_stream();
+    let mut decoder = InputDecoder::new();
+    CONSOLE_ACTIVE.store(true, Ordering::Relaxed);
+    log::debug!("serial console initialized");
+    while let Some(byte) = bytes.next().await {
+        if byte == crate::monitor::SERIAL_HOTKEY {
+            crate::monitor::request();
+        } else if let Some(character) = decoder.decode(byte) {
+            crate::console::input(character);
+        }
+    }
+}
+
+#[test_case]
+fn test_input_decoder() {
+    let mut decoder = InputDecoder::new();
+    let mut decode = |bytes: &[u8]| -> alloc::vec::Vec<char> {
+        bytes.iter().filter_map(|byte| decoder.decode(*byte)).collect()
+    };
+    assert_eq!(decode(b"ls\r"), ['l', 's', '\n']);
+    assert_eq!(decode(b"\x1b[A\x1b[B"), [EscapeChar::ScrollUp as u8 as char, EscapeChar::ScrollDown as u8 as char]);
+    assert_eq!(decode(b"\x1bOP"), [VirtualTerminals::KernelLog as u8 as char]);
+    assert_eq!(decode(b"\x1b[15~x"), [VirtualTerminals::Tasks as u8 as char, 'x']);
+    assert!(decode(b"\x1b[99~").is_empty());
+}
@@ -1,62 +1,38 @@
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use core::{pin::Pin, task::{Poll, Context}};
-use futures_util::stream::{Stream, StreamExt};
-use futures_util::task::AtomicWaker;
+use futures_util::stream::StreamExt;
+use crate::ipc::PipeReader;
 use crate::vga::term::TERM;
+use super::irq_queue::{IrqQueue, IrqStream};
 
-static TERM_QUEUE: OnceCell<ArrayQueue<char>> = OnceCell::uninit();
-static TERM_WAKER: AtomicWaker = AtomicWaker::new();
+static TERM_QUEUE: IrqQueue<char> = IrqQueue::new("terminal character", 1000);
 
 pub(crate) fn add_char(character: char) {
-    if let Ok(queue) = TERM_QUEUE.try_get() {
-        if let Err(_) = queue.push(character) {
-            log::warn!("terminal character queue full; dropping character");
-        } else {
-            TERM_WAKER.wake();
-        }
-    }
+    TERM_QUEUE.push(character);
 }
 
-pub struct CharacterStream {
-    _private: (),
+pub fn character_stream() -> IrqStream<'static, char> {
+    TERM_QUEUE.stream()
 }
 
-impl CharacterStream {
-    pub fn new() -> Self {
-        TERM_QUEUE.try_init_once(|| ArrayQueue::new(1000))
-            .expect("CharacterStream::new should be called once");
-        CharacterStream { _private: () }
+pub async fn process_buffer() {
+    let mut stream = character_stream();
+    log::debug!("terminal buffer initialized");
+    while let Some(character) = stream.next().await {
+        let mut term = TERM.lock();
+        term.write_byte(character as u8);
     }
 }
 
-impl Stream for CharacterStream {
-    type Item = char;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<char>> {
-        let queue = TERM_QUEUE.try_get().expect("terminal character queue should be initialized by now");
-
-        // fast path
-        if let Some(character) = queue.pop() {
-            return Poll::Ready(Some(character));
+/// Copies everything written to the pipe to the terminal, e.g. the output of a command
+pub async fn print_pipe(mut reader: PipeReader) {
+    let mut buf = [0; 64];
+    loop {
+        let len = reader.read(&mut buf).await;
+        if len == 0 {
+            break;
         }
-
-        TERM_WAKER.register(&cx.waker());
-        match queue.pop() {
-            Some(character) => {
-                TERM_WAKER.take();
-                Poll::Ready(Some(character))
-            }
-            None => Poll::Pending,
+        let mut term = TERM.lock();
+        for byte in &buf[..len] {
+            term.write_byte(*byte);
         }
     }
 }
-
-pub async fn process_buffer() {
-    let mut stream = CharacterStream::new();
-    log::debug!("terminal buffer initialized");
-    while let Some(character) = stream.next().await {
-        let mut term = TERM.lock();
-        term.write_byte(character as u8);
-    }
-}
\ No newline at end of file
@@ -1,5 +1,5 @@
 use alloc::vec::Vec;
-use vga::colors::{TextModeColor, Color16};
+use vga::colors::TextModeColor;
 use core::fmt;
 
 #[derive(Debug, Copy, Clone)]
@@ -10,7 +10,7 @@ pub struct BufferCharacter {
 
 impl BufferCharacter {
     pub fn default_color() -> TextModeColor {
-        TextModeColor::new(Color16::LightGrey, Color16::Black)
+        crate::vga::DEFAULT_COLOR
     }
 }
 
@@ -105,6 +105,12 @@ impl Textbuffer {
         }
     }
 
+    pub fn write_string_color(&mut self, s: &str, color: TextModeColor) {
+        for c in s.chars() {
+            self.write_char_color(c, color);
+        }
+    }
+
 }
 
 impl fmt::Write for Textbuffer {
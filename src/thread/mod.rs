@@ -0,0 +1,371 @@
+//! Preemptive kernel threads
+//!
+//! Every thread has its own stack and is switched to round-robin on each
+//! timer tick. The thread that calls `init` (the boot stack, which goes on
+//! to run the async executor) becomes the first thread.
+//!
+//! Threads only run on the bootstrap processor, which is the only one
+//! receiving timer interrupts. On the other CPUs, `yield_now` does nothing
+//! and `has_ready_threads` returns false.
+
+use alloc::{borrow::Cow, boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+mod switch;
+
+/// Stack size of spawned threads
+pub const STACK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Exited,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: Cow<'static, str>,
+    state: ThreadState,
+    /// Saved stack pointer while the thread is not running
+    rsp: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack
+    stack: Option<Vec<u8>>,
+    /// Level 4 page table loaded while the thread runs
+    address_space: PhysFrame,
+}
+
+impl Thread {
+    /// Top of the thread's own stack, which is also used when it
+    /// enters the kernel from user mode
+    fn stack_top(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(|stack| {
+            VirtAddr::new(stack.as_ptr() as u64 + stack.len() as u64).align_down(16u64)
+        })
+    }
+}
+
+/// Snapshot of a thread for listings
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: Cow<'static, str>,
+    pub state: ThreadState,
+}
+
+/// The scheduler is locked with interrupts disabled, so it must never
+/// allocate or free while locked: a preempted thread might hold the heap.
+/// Both tables are grown by `grow` with the lock released instead.
+struct Scheduler {
+    /// Sorted by id, boxed so the saved `rsp` does not move while a switch writes it
+    threads: Vec<Box<Thread>>,
+    /// Has room for every thread, so the timer interrupt never allocates here
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    /// The thread switched away from last, which is not reaped until the
+    /// next switch since `context_switch` saves its `rsp` after unlocking
+    previous: Option<ThreadId>,
+    /// Level 4 table of the thread that called `init`
+    kernel_address_space: PhysFrame,
+}
+
+impl Scheduler {
+    fn get_mut(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        let index = self.threads.binary_search_by_key(&id, |thread| thread.id).ok()?;
+        Some(&mut *self.threads[index])
+    }
+
+    fn has_room(&self) -> bool {
+        self.threads.len() < self.threads.capacity() && self.threads.len() < self.ready.capacity()
+    }
+}
+
+lazy_static! {
+    /// Only ever locked with interrupts disabled, since the timer interrupt takes it too
+    static ref SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+}
+
+/// Turns the currently running code into the first thread
+pub fn init(name: impl Into<Cow<'static, str>>) {
+    let id = ThreadId::new();
+    let kernel_address_space = Cr3::read().0;
+    let threads = vec![Box::new(Thread {
+        id,
+        name: name.into(),
+        state: ThreadState::Running,
+        rsp: 0,
+        stack: None,
+        address_space: kernel_address_space,
+    })];
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler {
+            threads,
+            ready: VecDeque::new(),
+            current: id,
+            previous: None,
+            kernel_address_space,
+        });
+    });
+}
+
+/// Starts a new kernel thread running `f`
+pub fn spawn<F>(name: impl Into<Cow<'static, str>>, f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut stack = vec![0u8; STACK_SIZE];
+    let rsp = switch::init_stack(&mut stack, Box::new(f));
+    // the id and address space are filled in once the scheduler is locked
+    let mut thread = Some(Box::new(Thread {
+        id: ThreadId(0),
+        name: name.into(),
+        state: ThreadState::Ready,
+        rsp,
+        stack: Some(stack),
+        address_space: Cr3::read().0,
+    }));
+
+    reap();
+    let id = loop {
+        let spawned = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut().expect("threads not initialized");
+            if !scheduler.has_room() {
+                return Err(scheduler.threads.len());
+            }
+            let mut thread = thread.take().unwrap();
+            // taken under the lock, so pushing keeps `threads` sorted
+            thread.id = ThreadId::new();
+            // new threads start in the kernel's address space, not in the spawner's
+            thread.address_space = scheduler.kernel_address_space;
+            let id = thread.id;
+            scheduler.threads.push(thread);
+            scheduler.ready.push_back(id);
+            Ok(id)
+        });
+        match spawned {
+            Ok(id) => break id,
+            Err(threads) => grow(threads + 1),
+        }
+    };
+    log::debug!("spawned thread {}", id);
+    id
+}
+
+/// Makes room for at least `threads` threads in the scheduler
+///
+/// The new tables are allocated and the old ones freed with the scheduler
+/// unlocked, only moving the entries over happens under the lock.
+fn grow(threads: usize) {
+    let capacity = threads.max(4).next_power_of_two();
+    let mut new_threads = Vec::with_capacity(capacity);
+    let mut new_ready = VecDeque::with_capacity(capacity);
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("threads not initialized");
+        if scheduler.threads.capacity() < capacity {
+            new_threads.extend(scheduler.threads.drain(..));
+            core::mem::swap(&mut scheduler.threads, &mut new_threads);
+        }
+        if scheduler.ready.capacity() < capacity {
+            new_ready.extend(scheduler.ready.drain(..));
+            core::mem::swap(&mut scheduler.ready, &mut new_ready);
+        }
+    });
+    // `new_threads` and `new_ready` now hold whichever tables are unused
+}
+
+/// Frees the stacks of exited threads
+///
+/// Must not be called from interrupt context, since it deallocates.
+fn reap() {
+    loop {
+        let exited = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut()?;
+            let (current, previous) = (scheduler.current, scheduler.previous);
+            let index = scheduler.threads.iter().position(|thread| {
+                thread.state == ThreadState::Exited
+                    && thread.id != current
+                    && Some(thread.id) != previous
+            })?;
+            // removing keeps the order and the capacity, it does not free
+            Some(scheduler.threads.remove(index))
+        });
+        match exited {
+            // dropped with the scheduler unlocked
+            Some(thread) => drop(thread),
+            None => break,
+        }
+    }
+}
+
+/// Switches to the next ready thread, if there is one
+///
+/// Must be called with interrupts disabled. Returns once this thread is
+/// scheduled again.
+fn schedule() {
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = match SCHEDULER.try_lock() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let scheduler = match scheduler.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+
+        let current = scheduler.current;
+        scheduler.previous = Some(current);
+        let old = scheduler.get_mut(current).expect("current thread missing");
+        let old_rsp = &mut old.rsp as *mut u64;
+        if old.state == ThreadState::Running {
+            old.state = ThreadState::Ready;
+            scheduler.ready.push_back(current);
+        }
+
+        scheduler.current = next;
+        let new = scheduler.get_mut(next).expect("ready thread missing");
+        new.state = ThreadState::Running;
+        if let Some(stack_top) = new.stack_top() {
+            unsafe { crate::gdt::set_kernel_stack(stack_top) };
+        }
+        let (active, flags) = Cr3::read();
+        if active != new.address_space {
+            // the kernel is mapped the same way in every address space
+            unsafe { Cr3::write(new.address_space, flags) };
+        }
+        (old_rsp, new.rsp)
+    };
+    // the lock is released before switching, the next thread may need it
+    unsafe { switch::context_switch(old_rsp, new_rsp) };
+}
+
+/// Called by the timer interrupt handler after the end of interrupt
+/// was signalled, since it might not return to the interrupted thread
+/// until that is scheduled again
+///
+/// Must not block or allocate
+pub(crate) fn preempt() {
+    schedule();
+}
+
+/// Returns false on the application processors, which do not run threads
+fn on_bsp() -> bool {
+    crate::smp::percpu::try_current().map_or(true, |cpu| cpu.is_bsp())
+}
+
+/// Gives the rest of the time slice to the next ready thread
+pub fn yield_now() {
+    if on_bsp() {
+        interrupts::without_interrupts(schedule);
+    }
+}
+
+/// Returns true if another thread is waiting to run
+pub fn has_ready_threads() -> bool {
+    if !on_bsp() {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_ref().map_or(false, |scheduler| !scheduler.ready.is_empty())
+    })
+}
+
+/// Terminates the current thread
+pub fn exit() -> ! {
+    log::debug!("thread {:?} exiting", current());
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("threads not initialized");
+        let current = scheduler.current;
+        if let Some(thread) = scheduler.get_mut(current) {
+            thread.state = ThreadState::Exited;
+        }
+    }
+    // an exited thread is never put back into the ready queue,
+    // so this only returns while there is nothing else to run
+    loop {
+        schedule();
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+    }
+}
+
+/// Switches the current thread to the address space with the given level 4 table
+///
+/// This function is unsafe because the caller must guarantee that the table
+/// maps the kernel the same way as the table the kernel booted with.
+pub unsafe fn set_address_space(level_4_frame: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("threads not initialized");
+        let current = scheduler.current;
+        let thread = scheduler.get_mut(current).expect("current thread missing");
+        thread.address_space = level_4_frame;
+        let (_, flags) = Cr3::read();
+        Cr3::write(level_4_frame, flags);
+    });
+}
+
+/// Returns false once thread `id` has exited
+pub fn is_alive(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.as_mut()
+            .and_then(|scheduler| scheduler.get_mut(id))
+            .map_or(false, |thread| thread.state != ThreadState::Exited)
+    })
+}
+
+pub fn current() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_ref().map(|scheduler| scheduler.current))
+}
+
+pub fn list() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        match scheduler.as_ref() {
+            Some(scheduler) => scheduler.threads.iter()
+                .map(|thread| ThreadInfo {
+                    id: thread.id,
+                    name: thread.name.clone(),
+                    state: thread.state,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    })
+}
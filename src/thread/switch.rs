@@ -0,0 +1,83 @@
+//! Low level context switching between kernel threads
+//!
+//! Only the callee-saved registers are saved explicitly, everything else is
+//! already saved by the caller of `context_switch` according to the C ABI
+//! (or by the `x86-interrupt` prologue when switching from an interrupt
+//! handler). The target disables SSE, so there is no FPU state to save.
+
+use alloc::boxed::Box;
+use core::arch::global_asm;
+
+/// Number of registers pushed by `context_switch` before saving `rsp`
+const SAVED_REGISTERS: usize = 6;
+
+global_asm!(
+    ".global context_switch",
+    "context_switch:",
+    // rdi: where to save the old stack pointer, rsi: new stack pointer
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    // first run of a thread, `rbx` holds the entry closure set up by `init_stack`
+    "mov rdi, rbx",
+    "call {thread_main}",
+    "ud2",
+    thread_main = sym thread_main,
+);
+
+extern "C" {
+    /// Saves the callee-saved registers and the stack pointer to `old_rsp`,
+    /// then resumes the thread whose stack pointer is `new_rsp`
+    ///
+    /// Must be called with interrupts disabled.
+    pub(super) fn context_switch(old_rsp: *mut u64, new_rsp: u64);
+
+    fn thread_trampoline();
+}
+
+pub(super) type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+/// Prepares a fresh stack so that switching to it starts `entry`
+///
+/// Returns the initial stack pointer to pass to `context_switch`.
+pub(super) fn init_stack(stack: &mut [u8], entry: Entry) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    // `ret` in `context_switch` pops the trampoline address, leaving the
+    // stack 16 byte aligned for the `call` into `thread_main`
+    let rsp = top - 8 - (SAVED_REGISTERS as u64) * 8;
+    let frame = rsp as *mut u64;
+    let entry = Box::into_raw(Box::new(entry));
+    unsafe {
+        // r15, r14, r13, r12
+        for i in 0..4 {
+            frame.add(i).write(0);
+        }
+        frame.add(4).write(entry as u64); // rbx
+        frame.add(5).write(0); // rbp
+        frame.add(6).write(thread_trampoline as u64);
+    }
+    rsp
+}
+
+extern "C" fn thread_main(entry: *mut Entry) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    // threads are always switched to with interrupts disabled
+    x86_64::instructions::interrupts::enable();
+    entry();
+    super::exit();
+}
@@ -7,6 +7,7 @@ static TIME: AtomicCell<usize> = AtomicCell::new(0);
 /// Must not block or allocate
 pub(crate) fn increment_time() {
     crate::task::canvasgame::next();
+    crate::ata::tick();
     TIME.fetch_add(1);
 }
 
@@ -7,6 +7,7 @@ use spin::Mutex;
 use num_enum::FromPrimitive;
 use crate::gui::{window::Window, GuiDrawable};
 use crate::peripheral::{IObserver};
+use crate::task::{TaskState, Priority};
 use ps2_mouse::MouseState;
 use pc_keyboard::{DecodedKey, KeyCode};
 
@@ -43,10 +44,19 @@ pub enum VirtualTerminals {
     GUI,
     CanvasGame,
     ScreenTest,
+    Tasks,
     #[num_enum(default)]
     Unknown,
 }
 
+const LOG_LEVELS: [log::LevelFilter; 5] = [
+    log::LevelFilter::Error,
+    log::LevelFilter::Warn,
+    log::LevelFilter::Info,
+    log::LevelFilter::Debug,
+    log::LevelFilter::Trace,
+];
+
 pub struct Term {
     pub active_term: VirtualTerminals,
     console: Mutex<Textbuffer>,
@@ -55,18 +65,21 @@ pub struct Term {
     scroll_row: usize,
     scroll_col: usize,
     mouse_pos: (usize, usize),
+    /// Least severe level shown in the KernelLog terminal
+    log_level: log::LevelFilter,
 }
 
 impl Term {
     pub fn new() -> Self {
         Self {
-            active_term: VirtualTerminals::Console,
+            active_term: crate::config::get().terminal,
             console: Mutex::new(Textbuffer::new()),
             col: 0,
             row: 0,
             scroll_row: 0,
             scroll_col: 0,
             mouse_pos: (0, 0),
+            log_level: log::LevelFilter::Trace,
         }
     }
 
@@ -86,7 +99,10 @@ impl Term {
                 lines = self.console.lock().get_lines(self.scroll_row, TEXTMODE_SIZE.1);
             },
             VirtualTerminals::KernelLog => {
-                lines = crate::klog::LOG_BUFFER.lock().get_lines(self.scroll_row, TEXTMODE_SIZE.1);
+                lines = crate::klog::with_rendered(self.log_level, |log| log.get_lines(self.scroll_row, TEXTMODE_SIZE.1));
+            },
+            VirtualTerminals::Tasks => {
+                lines = task_list().get_lines(self.scroll_row, TEXTMODE_SIZE.1);
             },
             VirtualTerminals::GUI => {
                 TEST_WINDOW.draw(writer.get_graphics_writer() as &dyn vga::writers::GraphicsWriter<Color16>);
@@ -250,7 +266,7 @@ impl Term {
         self.active_term = virtual_term;
         match self.active_term {
             VirtualTerminals::KernelLog => {
-                let (row, col) = crate::klog::LOG_BUFFER.lock().end_coord();
+                let (row, col) = crate::klog::with_rendered(self.log_level, Textbuffer::end_coord);
                 self.row = row;
                 self.col = col;
                 self.focus_cursor();
@@ -267,7 +283,7 @@ impl Term {
             VirtualTerminals::CanvasGame => {
                 self.update_screen();
             }
-            VirtualTerminals::ScreenTest => {
+            VirtualTerminals::ScreenTest | VirtualTerminals::Tasks => {
                 self.col = 0;
                 self.row = 0;
                 self.scroll_row = 0;
@@ -303,6 +319,9 @@ impl Term {
                             || self.col < self.scroll_col {
                             self.focus_cursor();
                         }
+                        if crate::task::serial::is_console_active() {
+                            crate::serial::console_write_byte(byte);
+                        }
                         if byte == b'\n' {
                             self.new_line();
                         } else {
@@ -319,9 +338,22 @@ impl Term {
                     byte if EscapeChar::from(byte) != EscapeChar::Null => self.handle_escape_char(EscapeChar::from(byte)),
                     byte if byte == 0x08 => log::trace!("Backspace"),
                     byte if byte == 0x00 => self.update_screen(),
+                    // 1 shows only errors, up to 5 for everything
+                    byte @ b'1'..=b'5' => {
+                        self.log_level = LOG_LEVELS[usize::from(byte - b'1')];
+                        self.change_focus(VirtualTerminals::KernelLog);
+                    }
                     _ => {},
                 }
             },
+            VirtualTerminals::Tasks => {
+                match byte {
+                    byte if VirtualTerminals::from(byte) != VirtualTerminals::Unknown => self.change_focus(VirtualTerminals::from(byte)),
+                    byte if EscapeChar::from(byte) != EscapeChar::Null => self.handle_escape_char(EscapeChar::from(byte)),
+                    // any other key takes a new snapshot
+                    _ => self.update_screen(),
+                }
+            },
             _ => {
                 match byte {
                     byte if VirtualTerminals::from(byte) != VirtualTerminals::Unknown => self.change_focus(VirtualTerminals::from(byte)),
@@ -339,6 +371,43 @@ impl Term {
     }
 }
 
+/// Renders a `ps`-like table of the executor's tasks
+fn task_list() -> Textbuffer {
+    use core::fmt::Write;
+
+    let tasks = crate::task::info::list();
+    let total_cycles = tasks.iter().map(|task| task.poll_cycles).sum::<u64>().max(1);
+
+    let mut buffer = Textbuffer::new();
+    write!(buffer, "{:>4} {:<12} {:<6} {:<8} {:>8} {:>12} {:>4} {:>10} {:>7}",
+        "ID", "NAME", "PRI", "STATE", "POLLS", "CYCLES", "CPU", "AVG", "SPAWNED").ok();
+    for task in tasks {
+        buffer.new_line();
+        let state = match task.state {
+            TaskState::Ready => "ready",
+            TaskState::Pending => "pending",
+            TaskState::Finished => "finished",
+        };
+        let priority = match task.priority {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        };
+        write!(buffer, "{:>4} {:<12.12} {:<6} {:<8} {:>8} {:>12} {:>3}% {:>10} {:>7}",
+            task.id,
+            task.name,
+            priority,
+            state,
+            task.poll_count,
+            task.poll_cycles,
+            task.poll_cycles * 100 / total_cycles,
+            task.poll_cycles / task.poll_count.max(1),
+            task.spawned_at,
+        ).ok();
+    }
+    buffer
+}
+
 pub struct TermInput {
     _private: (),
 }
@@ -357,8 +426,10 @@ impl IObserver<DecodedKey> for TermInput {
             DecodedKey::RawKey(KeyCode::F2) => add_char(VirtualTerminals::Console as u8 as char),
             DecodedKey::RawKey(KeyCode::F3) => add_char(VirtualTerminals::GUI as u8 as char),
             DecodedKey::RawKey(KeyCode::F4) => add_char(VirtualTerminals::CanvasGame as u8 as char),
+            DecodedKey::RawKey(KeyCode::F5) => add_char(VirtualTerminals::Tasks as u8 as char),
+            DecodedKey::RawKey(KeyCode::F10) => crate::monitor::request(),
             DecodedKey::RawKey(KeyCode::F12) => add_char(VirtualTerminals::ScreenTest as u8 as char),
-            DecodedKey::Unicode(character) => add_char(character),
+            DecodedKey::Unicode(character) => crate::console::input(character),
             DecodedKey::RawKey(key) => add_char(key as u8 as char),
         }
     }
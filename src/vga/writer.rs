@@ -114,6 +114,15 @@ impl Writer {
         }
     }
 
+    /// Erases the character left of the cursor, in text mode
+    pub fn backspace(&mut self) {
+        if self.mode == WriterMode::Text && self.col > 0 {
+            self.col -= 1;
+            self.text.write_character(self.col, self.row, ScreenCharacter::new(b' ', DEFAULT_COLOR));
+            self.update_cursor();
+        }
+    }
+
     pub fn clear_row(&mut self, row: usize) {
         let character = ScreenCharacter::new(b' ', DEFAULT_COLOR);
         match self.mode {
@@ -169,11 +178,10 @@ impl Writer {
                 for row in 0..TEXTMODE_SIZE.1 {
                     if row < buf.len() {
                         for col in 0..TEXTMODE_SIZE.0 {
-                            let mut character = b' ';
-                            if col < buf[row].chars.len() {
-                                character = buf[row].chars[col].character as u8;
-                            }
-                            let screen_char = ScreenCharacter::new(character, DEFAULT_COLOR);
+                            let screen_char = match buf[row].chars.get(col) {
+                                Some(buffer_char) => ScreenCharacter::new(buffer_char.character as u8, buffer_char.color),
+                                None => ScreenCharacter::new(b' ', DEFAULT_COLOR),
+                            };
                             self.text.write_character(col, row, screen_char);
                         }
                     } else {
@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_stuff::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+extern crate rlibc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use rust_stuff::block::{self, BlockDevice, BlockError};
+use rust_stuff::hlt_loop;
+use rust_stuff::task::{Task, simple_executor::SimpleExecutor};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// Sectors of `target/ata-test.img`, see `runner.sh`
+const DISK_SECTORS: u64 = 16384;
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use rust_stuff::allocator;
+    use rust_stuff::memory::{self, BootInfoFrameAllocator};
+
+    rust_stuff::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().expect("no physical_memory_offset"));
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, rust_stuff::config::get().heap_size, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(phys_mem_offset, frame_allocator);
+    rust_stuff::ata::init();
+    rust_stuff::pci::init(None);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_stuff::test_panic_handler(info)
+}
+
+/// The test image, attached as the primary slave
+fn disk() -> Arc<dyn BlockDevice> {
+    block::by_name("ata1").expect("test disk not found, is target/ata-test.img attached?")
+}
+
+fn run(future: impl Future<Output = ()> + 'static) {
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(future));
+    executor.run();
+}
+
+/// Distinct bytes for every sector, so misplaced sectors show up
+fn pattern(lba: u64, sectors: usize) -> Vec<u8> {
+    (0..sectors * 512).map(|index| (lba as usize * 7 + index / 512 * 3 + index) as u8).collect()
+}
+
+async fn round_trip(disk: &dyn BlockDevice, lba: u64, sectors: usize) {
+    let data = pattern(lba, sectors);
+    disk.write_blocks(lba, &data).await.expect("write failed");
+    let mut read = vec![0; data.len()];
+    disk.read_blocks(lba, &mut read).await.expect("read failed");
+    assert!(read == data, "read back other data at LBA {}", lba);
+}
+
+#[test_case]
+fn finds_test_disk() {
+    let disk = disk();
+    assert_eq!(disk.block_size(), 512);
+    assert_eq!(disk.block_count(), DISK_SECTORS);
+}
+
+#[test_case]
+fn round_trips_lba28() {
+    run(async { round_trip(&*disk(), 1, 4).await });
+}
+
+#[test_case]
+fn round_trips_across_chunks() {
+    // more than the 256 sectors of one command
+    run(async { round_trip(&*disk(), 100, 300).await });
+}
+
+#[test_case]
+fn round_trips_lba48() {
+    run(async {
+        let disk = disk();
+        rust_stuff::ata::force_lba48(true);
+        round_trip(&*disk, DISK_SECTORS - 300, 300).await;
+        rust_stuff::ata::force_lba48(false);
+        // the same sectors through LBA28
+        let mut read = vec![0; 300 * 512];
+        disk.read_blocks(DISK_SECTORS - 300, &mut read).await.expect("read failed");
+        assert!(read == pattern(DISK_SECTORS - 300, 300));
+    });
+}
+
+#[test_case]
+fn keeps_neighbouring_sectors() {
+    run(async {
+        let disk = disk();
+        round_trip(&*disk, 1000, 3).await;
+        disk.write_blocks(1001, &[0xee; 512]).await.expect("write failed");
+        let mut read = vec![0; 3 * 512];
+        disk.read_blocks(1000, &mut read).await.expect("read failed");
+        let expected = pattern(1000, 3);
+        assert!(read[..512] == expected[..512]);
+        assert!(read[512..1024].iter().all(|byte| *byte == 0xee));
+        assert!(read[1024..] == expected[1024..]);
+    });
+}
+
+#[test_case]
+fn rejects_bad_requests() {
+    run(async {
+        let disk = disk();
+        let mut buf = vec![0; 512];
+        assert_eq!(disk.read_blocks(DISK_SECTORS, &mut buf).await, Err(BlockError::OutOfRange));
+        assert_eq!(disk.write_blocks(DISK_SECTORS - 1, &[0; 1024]).await, Err(BlockError::OutOfRange));
+        assert_eq!(disk.read_blocks(0, &mut buf[..100]).await, Err(BlockError::BadBuffer));
+    });
+}
+
+#[test_case]
+fn accepts_empty_requests() {
+    run(async {
+        let disk = disk();
+        assert_eq!(disk.write_blocks(0, &[]).await, Ok(()));
+        assert_eq!(disk.read_blocks(DISK_SECTORS, &mut []).await, Ok(()));
+    });
+}
@@ -20,7 +20,7 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     
-    allocator::init_heap(&mut mapper, 1024 * 1024 * 4, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap(&mut mapper, rust_stuff::config::get().heap_size, &mut frame_allocator).expect("heap initialization failed");
 
     test_main();
     hlt_loop();
@@ -0,0 +1,25 @@
+# Writes its last argument to the serial port and exits with argc
+#
+# Rebuild with:
+#   as args.s -o args.o && ld -static -nostdlib -Ttext-segment=0x100000000000 args.o -o args.elf
+
+    .global _start
+    .text
+_start:
+    mov (%rsp), %r12        # argc
+    mov (%rsp, %r12, 8), %rsi   # argv[argc - 1]
+
+    # strlen
+    xor %rdx, %rdx
+1:  cmpb $0, (%rsi, %rdx)
+    je 2f
+    inc %rdx
+    jmp 1b
+
+2:  mov $0, %rax            # write
+    mov $2, %rdi            # serial port
+    syscall
+
+    mov $1, %rax            # exit
+    mov %r12, %rdi
+    syscall
@@ -0,0 +1,14 @@
+# Reads kernel memory, which must kill the process instead of the kernel
+#
+# Rebuild with:
+#   as fault.s -o fault.o && ld -static -nostdlib -Ttext-segment=0x100000000000 fault.o -o fault.elf
+
+    .global _start
+    .text
+_start:
+    mov $0xffff800000000000, %rax
+    mov (%rax), %rax
+
+    mov $1, %rax            # exit, not reached
+    xor %rdi, %rdi
+    syscall
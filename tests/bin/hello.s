@@ -0,0 +1,22 @@
+# Writes a greeting to the serial port and exits with the bytes written
+#
+# Rebuild with:
+#   as hello.s -o hello.o && ld -static -nostdlib -Ttext-segment=0x100000000000 hello.o -o hello.elf
+
+    .global _start
+    .text
+_start:
+    mov $0, %rax            # write
+    mov $2, %rdi            # serial port
+    lea message(%rip), %rsi
+    mov $message_len, %rdx
+    syscall
+
+    mov %rax, %rdi
+    mov $1, %rax            # exit
+    syscall
+
+    .data
+message:
+    .ascii "hello from ring 3\n"
+    .set message_len, . - message
@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_stuff::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+extern crate rlibc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_stuff::hlt_loop;
+use rust_stuff::process::{self, AddressSpace, ElfError, ElfFile};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// Test programs, see `tests/bin/*.s` for their sources
+static HELLO: &[u8] = include_bytes!("bin/hello.elf");
+static ARGS: &[u8] = include_bytes!("bin/args.elf");
+static FAULT: &[u8] = include_bytes!("bin/fault.elf");
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use rust_stuff::allocator;
+    use rust_stuff::memory::{self, BootInfoFrameAllocator};
+
+    rust_stuff::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().expect("no physical_memory_offset"));
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    allocator::init_heap(&mut mapper, rust_stuff::config::get().heap_size, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(phys_mem_offset, frame_allocator);
+    rust_stuff::thread::init("test");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_stuff::test_panic_handler(info)
+}
+
+#[test_case]
+fn parses_test_programs() {
+    for program in [HELLO, ARGS, FAULT].iter() {
+        let elf = ElfFile::parse(program).expect("test program rejected");
+        assert!(elf.entry() >= process::USER_START);
+    }
+}
+
+#[test_case]
+fn rejects_bad_magic() {
+    let mut data = alloc::vec::Vec::from(HELLO);
+    data[1] = b'X';
+    assert!(matches!(ElfFile::parse(&data), Err(ElfError::BadMagic)));
+}
+
+#[test_case]
+fn rejects_truncated_file() {
+    assert!(matches!(ElfFile::parse(&HELLO[..32]), Err(ElfError::Truncated)));
+    assert!(matches!(ElfFile::parse(&HELLO[..HELLO.len() / 4]), Err(ElfError::Truncated)));
+}
+
+#[test_case]
+fn rejects_segment_outside_user_region() {
+    let mut data = alloc::vec::Vec::from(HELLO);
+    // vaddr of the first program header, moved into the kernel half
+    let vaddr = 64 + 16;
+    data[vaddr..vaddr + 8].copy_from_slice(&0xffff_8000_0000_0000u64.to_le_bytes());
+    assert!(matches!(ElfFile::parse(&data), Err(ElfError::SegmentOutsideUserRegion)));
+}
+
+#[test_case]
+fn loads_segments_with_permissions() {
+    let elf = ElfFile::parse(HELLO).unwrap();
+    let mut address_space = AddressSpace::new().unwrap();
+    elf.load(&mut address_space).unwrap();
+    for header in elf.program_headers().filter(|header| header.kind == process::elf::PT_LOAD) {
+        let start = VirtAddr::new(header.vaddr);
+        let writable = header.flags & process::elf::PF_W != 0;
+        assert!(address_space.is_accessible(start, header.mem_size, false));
+        assert_eq!(address_space.is_accessible(start, header.mem_size, true), writable);
+    }
+}
+
+#[test_case]
+fn builds_initial_stack() {
+    let elf = ElfFile::parse(ARGS).unwrap();
+    let mut address_space = AddressSpace::new().unwrap();
+    elf.load(&mut address_space).unwrap();
+    process::map_stack(&mut address_space).unwrap();
+    let stack_pointer = elf.init_stack(&mut address_space, process::USER_STACK_TOP, &["args", "test"], &["A=1"]).unwrap();
+    assert_eq!(stack_pointer.as_u64() % 16, 0);
+    assert!(stack_pointer < process::USER_STACK_TOP);
+    assert!(address_space.is_accessible(stack_pointer, process::USER_STACK_TOP - stack_pointer, true));
+
+    let word = |index: u64| {
+        let mut bytes = [0; 8];
+        address_space.read(stack_pointer + index * 8, &mut bytes).unwrap();
+        u64::from_le_bytes(bytes)
+    };
+    let string = |addr: u64| {
+        let mut bytes = [0; 8];
+        address_space.read(VirtAddr::new(addr), &mut bytes).unwrap();
+        let len = bytes.iter().position(|byte| *byte == 0).expect("string not terminated");
+        alloc::vec::Vec::from(&bytes[..len])
+    };
+    // argc, argv, null, envp, null
+    assert_eq!(word(0), 2);
+    assert_eq!(string(word(1)), b"args");
+    assert_eq!(string(word(2)), b"test");
+    assert_eq!(word(3), 0);
+    assert_eq!(string(word(4)), b"A=1");
+    assert_eq!(word(5), 0);
+}
+
+#[test_case]
+fn runs_test_programs() {
+    let hello = process::spawn_elf("hello", HELLO, &["hello"], &[]).unwrap();
+    let args = process::spawn_elf("args", ARGS, &["args", "from the test\n"], &[]).unwrap();
+    // killed by its page fault, the kernel keeps running
+    let fault = process::spawn_elf("fault", FAULT, &["fault"], &[]).unwrap();
+    let mut codes = [None; 3];
+    while codes.iter().any(Option::is_none) {
+        for (code, id) in codes.iter_mut().zip([hello, args, fault]) {
+            if code.is_none() {
+                *code = process::try_wait(id);
+            }
+        }
+        rust_stuff::thread::yield_now();
+    }
+    // hello exits with the bytes it wrote, args with its argc
+    assert_eq!(codes, [Some(18), Some(2), Some(-1)]);
+}
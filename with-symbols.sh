@@ -0,0 +1,35 @@
+#!/bin/sh
+# Runs a cargo command on a kernel that can name the functions in backtraces
+#
+# Builds once, takes the symbols of that build and passes them to the
+# requested command, see src/backtrace/symbols.rs. E.g.:
+#   ./with-symbols.sh run
+#   ./with-symbols.sh build --release
+#
+# The symbols are those of the kernel binary, so only commands building
+# that binary get correct names. Test binaries have their own layout and
+# would print wrong names, so `test` and `bench` are refused.
+set -e
+
+case "$1" in
+    test|bench)
+        echo "with-symbols.sh: the symbols only fit the kernel binary, not $1 binaries" >&2
+        exit 1
+        ;;
+esac
+
+profile=debug
+for arg in "$@"; do
+    if [ "$arg" = "--release" ]; then
+        profile=release
+    fi
+done
+
+cd "$(dirname "$0")"
+if [ "$profile" = release ]; then
+    cargo build --release
+else
+    cargo build
+fi
+nm -n -S -C "target/x86_64-bare_os/$profile/rust-stuff" > target/kernel.sym
+KERNEL_SYMBOL_MAP="$PWD/target/kernel.sym" cargo "$@"